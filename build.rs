@@ -0,0 +1,77 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// with the `embed` feature, every file under $EMBED_DIR (default `static/`) is compiled
+// into the binary, see src/embedded.rs
+fn main() {
+    println!("cargo:rerun-if-env-changed=EMBED_DIR");
+
+    let mut files = Vec::new();
+    if env::var_os("CARGO_FEATURE_EMBED").is_some() {
+        let explicit = env::var("EMBED_DIR").ok();
+        let root = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join(explicit.as_deref().unwrap_or("static"));
+        // also picks up the directory appearing later
+        println!("cargo:rerun-if-changed={}", root.display());
+        if root.is_dir() {
+            collect(&root, &root, &mut files);
+            files.sort();
+        } else {
+            // a mistyped $EMBED_DIR is an error, a tree without `static/` embeds nothing
+            assert!(
+                explicit.is_none(),
+                "embed: {} is not a directory",
+                root.display()
+            );
+            println!(
+                "cargo:warning=embed: no {} directory, nothing is embedded",
+                root.display()
+            );
+        }
+    }
+
+    let mut generated = String::from("pub(crate) static FILES: &[EmbeddedFile] = &[\n");
+    for (name, path) in files {
+        let content = fs::read(&path).unwrap();
+        writeln!(
+            generated,
+            "    EmbeddedFile {{ name: {name:?}, content: include_bytes!({:?}), etag: \"\\\"{:016x}\\\"\" }},",
+            path.display(),
+            fnv1a(&content)
+        )
+        .unwrap();
+    }
+    generated.push_str("];\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("embedded.rs");
+    fs::write(out, generated).unwrap();
+}
+
+// (name relative to `root` with `/` separators, path)
+fn collect(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            collect(root, &path, files);
+        } else {
+            let name = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((name, path));
+        }
+    }
+}
+
+// good enough to tell versions of a file apart, computed once at build time
+fn fnv1a(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
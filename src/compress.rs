@@ -0,0 +1,44 @@
+// gzip and deflate bodies held in memory, on a deflate encoder reused per thread
+
+use std::cell::RefCell;
+use std::io::Write;
+
+use anyhow::Result;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+thread_local! {
+    // the compressor state is the expensive part, each worker keeps its own and
+    // resets it between responses instead of allocating a new one
+    static DEFLATE: RefCell<DeflateEncoder<Vec<u8>>> =
+        RefCell::new(DeflateEncoder::new(Vec::new(), Compression::default()));
+}
+
+// what flate2's GzEncoder writes: no name, no mtime, unknown OS
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0x00, 0xff];
+
+fn deflate_into(output: Vec<u8>, content: &[u8]) -> Result<Vec<u8>> {
+    DEFLATE.with(|encoder| {
+        let mut encoder = encoder.borrow_mut();
+        encoder.reset(output)?;
+        encoder.write_all(content)?;
+        encoder.try_finish()?;
+        Ok(std::mem::take(encoder.get_mut()))
+    })
+}
+
+pub(crate) fn gzip(content: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(GZIP_HEADER.len() + content.len() / 2 + 8);
+    output.extend_from_slice(&GZIP_HEADER);
+    let mut output = deflate_into(output, content)?;
+
+    let mut crc = Crc::new();
+    crc.update(content);
+    output.extend_from_slice(&crc.sum().to_le_bytes());
+    output.extend_from_slice(&crc.amount().to_le_bytes());
+    Ok(output)
+}
+
+pub(crate) fn deflate(content: &[u8]) -> Result<Vec<u8>> {
+    deflate_into(Vec::with_capacity(content.len() / 2), content)
+}
@@ -0,0 +1,598 @@
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use crate::log;
+use crate::signal;
+
+// latency injection is for testing clients, never sleep longer than this
+pub(crate) const MAX_INJECTED_DELAY: Duration = Duration::from_secs(10);
+
+// one year, what browsers' preload lists ask for
+const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub directory: String,
+    // /files/ comes from the binary (`embed` feature) since no directory was given
+    pub embedded: bool,
+    pub keep_alive: bool,
+    pub access_log: Option<String>,
+    pub force_download: bool,
+    // guess the type of extension-less files from their first bytes
+    pub sniff: bool,
+    pub bind_unix: Option<String>,
+    // an already listening socket inherited from the parent (socket activation)
+    pub listen_fd: Option<i32>,
+    // `None` unless --inject-delay is given, which also unlocks `?delay=MS`
+    pub inject_delay: Option<Duration>,
+    // scheme clients reach us with, https when behind a TLS terminating proxy
+    pub scheme: String,
+    pub max_header_count: usize,
+    pub max_uri_length: usize,
+    // of a single header line, terminator included
+    pub max_line_length: usize,
+    // waiting for the next request (or the first one) before giving the worker
+    // back, `None` to wait forever
+    pub keep_alive_timeout: Option<Duration>,
+    // from the first byte of a request to the end of its headers, `None` to wait forever
+    pub header_timeout: Option<Duration>,
+    // reject what lenient parsing would repair, like doubled spaces in the request line
+    pub strict: bool,
+    // collapse `//` in request paths, off for setups where they are significant
+    pub merge_slashes: bool,
+    // advertised on text files served from the directory
+    pub charset: String,
+    // worker pool bounds, extra workers exit after `idle_timeout` without work
+    pub min_threads: usize,
+    pub max_threads: usize,
+    // connections waiting for a worker, past that they are turned away with a 503
+    pub max_queue: Option<usize>,
+    pub idle_timeout: Duration,
+    // kept-alive connections allowed to wait for a next request, past that the ones
+    // waiting the longest are closed
+    pub max_idle_connections: Option<usize>,
+    // connections waiting that long for a request are closed by a reaper thread
+    pub reap_idle: Option<Duration>,
+    // prefix of the worker thread names
+    pub thread_name: String,
+    pub verbose: bool,
+    // Strict-Transport-Security max-age, only honored when `scheme` is https
+    pub hsts_max_age: Option<u64>,
+    pub redirect_port: Option<u16>,
+    // set on the config of the --redirect-port listener, not from the command line
+    pub https_redirect: bool,
+    // X-Forwarded-Proto is set by a proxy in front of us rather than the client
+    pub trust_proxy: bool,
+    pub bind: IpAddr,
+    pub port: u16,
+    // no uploads at all
+    pub read_only: bool,
+    // permissions of uploaded files instead of the umask default, unix only
+    pub upload_mode: Option<u32>,
+    pub allow_public_writes: bool,
+    // /headers masks credentials unless asked not to
+    pub show_sensitive_headers: bool,
+    // `Name: Value` lines added to every response, re-read on SIGHUP
+    pub headers_file: Option<String>,
+    pub extra_headers: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            directory: ".".into(),
+            embedded: false,
+            keep_alive: true,
+            access_log: None,
+            force_download: false,
+            sniff: false,
+            bind_unix: None,
+            listen_fd: None,
+            inject_delay: None,
+            scheme: "http".into(),
+            max_header_count: 100,
+            max_uri_length: 8192,
+            max_line_length: 8192,
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            header_timeout: Some(Duration::from_secs(10)),
+            strict: false,
+            merge_slashes: true,
+            charset: "utf-8".into(),
+            min_threads: 4,
+            max_threads: 64,
+            max_queue: None,
+            idle_timeout: Duration::from_secs(30),
+            max_idle_connections: None,
+            reap_idle: None,
+            thread_name: "http-worker".into(),
+            verbose: false,
+            hsts_max_age: None,
+            redirect_port: None,
+            https_redirect: false,
+            trust_proxy: false,
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 4221,
+            read_only: false,
+            upload_mode: None,
+            allow_public_writes: false,
+            show_sensitive_headers: false,
+            headers_file: None,
+            extra_headers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // reachable from other machines, not just this one
+    pub(crate) fn is_public(&self) -> bool {
+        !self.bind.is_loopback()
+    }
+
+    // files compiled into the binary cannot be written to
+    pub(crate) fn accepts_uploads(&self) -> bool {
+        !self.read_only && !self.embedded
+    }
+
+    // anyone on the network writing to the directory has to be asked for
+    pub(crate) fn check_public_writes(&self) -> Result<(), String> {
+        if self.is_public() && self.accepts_uploads() && !self.allow_public_writes {
+            return Err(format!(
+                "refusing to accept uploads from the network on {}: pass --read-only or --allow-public-writes",
+                self.bind
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_https(&self) -> bool {
+        self.scheme.eq_ignore_ascii_case("https")
+    }
+
+    // the command line and the files it names
+    pub(crate) fn load(argv: &[String]) -> Result<Self, String> {
+        check_args(argv)?;
+        let mut config = Self::parse(argv, env::var("HTTP_SERVER_DIR").ok());
+        if let Some(path) = &config.headers_file {
+            config.extra_headers = read_headers_file(path)?;
+        }
+        Ok(config)
+    }
+
+    fn parse(argv: &[String], env_directory: Option<String>) -> Self {
+        let default = Config::default();
+        Config {
+            embedded: cfg!(feature = "embed")
+                && arg_value(argv, "--directory").is_none()
+                && env_directory.as_deref().map_or(true, str::is_empty),
+            directory: resolve_directory(argv, env_directory),
+            keep_alive: !has_flag(argv, "--no-keepalive"),
+            access_log: arg_value(argv, "--access-log"),
+            force_download: has_flag(argv, "--force-download"),
+            sniff: has_flag(argv, "--sniff"),
+            bind_unix: arg_value(argv, "--bind-unix"),
+            listen_fd: arg_value(argv, "--listen-fd").and_then(|fd| parse_fd(&fd)),
+            inject_delay: arg_value(argv, "--inject-delay")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(|ms| Duration::from_millis(ms).min(MAX_INJECTED_DELAY)),
+            scheme: arg_value(argv, "--scheme").unwrap_or(default.scheme),
+            max_header_count: arg_value(argv, "--max-header-count")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(default.max_header_count),
+            max_uri_length: arg_value(argv, "--max-uri-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(default.max_uri_length),
+            max_line_length: arg_value(argv, "--max-line-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(default.max_line_length),
+            keep_alive_timeout: match arg_value(argv, "--keep-alive-timeout")
+                .map(|secs| secs.parse())
+            {
+                Some(Ok(0)) => None,
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                _ => default.keep_alive_timeout,
+            },
+            strict: has_flag(argv, "--strict"),
+            header_timeout: match arg_value(argv, "--header-timeout").map(|secs| secs.parse()) {
+                Some(Ok(0)) => None,
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                _ => default.header_timeout,
+            },
+            merge_slashes: !has_flag(argv, "--keep-double-slashes"),
+            charset: arg_value(argv, "--charset").unwrap_or(default.charset),
+            min_threads: arg_value(argv, "--min-threads")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(default.min_threads),
+            max_threads: arg_value(argv, "--max-threads")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(default.max_threads),
+            max_queue: arg_value(argv, "--max-queue").and_then(|max| max.parse().ok()),
+            idle_timeout: arg_value(argv, "--idle-timeout")
+                .and_then(|secs| secs.parse().ok())
+                .map_or(default.idle_timeout, Duration::from_secs),
+            max_idle_connections: arg_value(argv, "--max-idle-connections")
+                .and_then(|count| count.parse().ok()),
+            reap_idle: arg_value(argv, "--reap-idle")
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs),
+            thread_name: arg_value(argv, "--thread-name").unwrap_or(default.thread_name),
+            verbose: has_flag(argv, "--verbose"),
+            hsts_max_age: has_flag(argv, "--hsts").then(|| {
+                arg_value(argv, "--hsts-max-age")
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(DEFAULT_HSTS_MAX_AGE)
+            }),
+            redirect_port: arg_value(argv, "--redirect-port").and_then(|port| port.parse().ok()),
+            https_redirect: false,
+            trust_proxy: has_flag(argv, "--trust-proxy"),
+            bind: arg_value(argv, "--bind")
+                .and_then(|addr| addr.parse().ok())
+                .unwrap_or(default.bind),
+            port: arg_value(argv, "--port")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(default.port),
+            read_only: has_flag(argv, "--read-only"),
+            upload_mode: arg_value(argv, "--upload-mode").and_then(|mode| parse_mode(&mode)),
+            allow_public_writes: has_flag(argv, "--allow-public-writes"),
+            show_sensitive_headers: has_flag(argv, "--show-sensitive-headers"),
+            headers_file: arg_value(argv, "--headers-file"),
+            extra_headers: Vec::new(),
+        }
+    }
+}
+
+// the config requests are served with: rebuilt from the command line after a SIGHUP,
+// requests in flight keep the snapshot they started with. Listeners and the worker
+// pool are set up once and do not follow.
+pub(crate) struct LiveConfig {
+    // `None` for a config that is never reloaded
+    argv: Option<Vec<String>>,
+    current: RwLock<Arc<Config>>,
+    // SIGHUPs seen at the last reload
+    hangups: AtomicUsize,
+    // the --redirect-port listener: every reload keeps `https_redirect` set
+    https_redirect: bool,
+}
+
+impl LiveConfig {
+    pub(crate) fn new(config: Config, argv: Vec<String>) -> Self {
+        LiveConfig {
+            argv: Some(argv),
+            ..Self::fixed(config)
+        }
+    }
+
+    pub(crate) fn fixed(config: Config) -> Self {
+        LiveConfig {
+            argv: None,
+            https_redirect: config.https_redirect,
+            current: RwLock::new(Arc::new(config)),
+            hangups: AtomicUsize::new(signal::hangups()),
+        }
+    }
+
+    // the same command line, reloaded the same way, for the --redirect-port listener
+    pub(crate) fn redirecting(&self) -> Self {
+        LiveConfig {
+            argv: self.argv.clone(),
+            ..Self::fixed(Config {
+                https_redirect: true,
+                ..(*self.get()).clone()
+            })
+        }
+    }
+
+    pub(crate) fn get(&self) -> Arc<Config> {
+        let hangups = signal::hangups();
+        if self.argv.is_some() && self.hangups.swap(hangups, Ordering::SeqCst) != hangups {
+            self.reload();
+        }
+        Arc::clone(&self.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    // a config that does not load leaves the current one in place
+    pub(crate) fn reload(&self) {
+        let Some(argv) = &self.argv else {
+            return;
+        };
+        match Config::load(argv) {
+            Ok(mut config) => {
+                config.https_redirect |= self.https_redirect;
+                *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+                log::debug("configuration reloaded");
+            }
+            Err(e) => log::error(&format!("keeping the current configuration: {e}")),
+        }
+    }
+}
+
+// a flag, whether a value is one it takes, and what it takes
+type ValuedFlag = (&'static str, fn(&str) -> bool, &'static str);
+
+const VALUED_FLAGS: &[ValuedFlag] = &[
+    (
+        "--upload-mode",
+        |mode| parse_mode(mode).is_some(),
+        "an octal mode such as 0640",
+    ),
+    (
+        "--listen-fd",
+        |fd| parse_fd(fd).is_some(),
+        "a file descriptor number",
+    ),
+    ("--inject-delay", parses::<u64>, "a number of milliseconds"),
+    ("--max-header-count", parses::<usize>, "a count"),
+    ("--max-uri-length", parses::<usize>, "a length in bytes"),
+    ("--max-line-length", parses::<usize>, "a length in bytes"),
+    ("--header-timeout", parses::<u64>, "a number of seconds"),
+    ("--keep-alive-timeout", parses::<u64>, "a number of seconds"),
+    ("--min-threads", parses::<usize>, "a count"),
+    ("--max-threads", parses::<usize>, "a count"),
+    ("--max-queue", parses::<usize>, "a count"),
+    ("--idle-timeout", parses::<u64>, "a number of seconds"),
+    ("--max-idle-connections", parses::<usize>, "a count"),
+    ("--reap-idle", parses::<u64>, "a number of seconds"),
+    ("--hsts-max-age", parses::<u64>, "a number of seconds"),
+    ("--redirect-port", parses::<u16>, "a port number"),
+    ("--bind", parses::<IpAddr>, "an IP address"),
+    ("--port", parses::<u16>, "a port number"),
+];
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.parse::<T>().is_ok()
+}
+
+// a mistyped value would otherwise be quietly replaced by the default, refuse to
+// start instead (a reload keeps the running config)
+fn check_args(argv: &[String]) -> Result<(), String> {
+    for (flag, valid, expected) in VALUED_FLAGS {
+        if let Some(value) = arg_value(argv, flag).filter(|value| !valid(value)) {
+            return Err(format!("invalid {flag} {value:?}: expected {expected}"));
+        }
+    }
+    Ok(())
+}
+
+// octal permission bits, `0640` or `0o640`
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+}
+
+fn parse_fd(fd: &str) -> Option<i32> {
+    fd.parse().ok().filter(|fd| *fd >= 0)
+}
+
+fn read_headers_file(path: &str) -> Result<Vec<(String, String)>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let (name, value) = line
+                .split_once(':')
+                .filter(|(name, _)| !name.trim().is_empty())
+                .ok_or_else(|| format!("{path}:{number}: expected `Name: Value`"))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// served directory: --directory, then $HTTP_SERVER_DIR, then the working directory
+fn resolve_directory(argv: &[String], env_directory: Option<String>) -> String {
+    arg_value(argv, "--directory")
+        .or(env_directory.filter(|dir| !dir.is_empty()))
+        .or_else(|| {
+            env::current_dir()
+                .ok()
+                .map(|dir| dir.to_string_lossy().into_owned())
+        })
+        .unwrap_or(Config::default().directory)
+}
+
+fn arg_value(argv: &[String], name: &str) -> Option<String> {
+    let mut args = argv.iter().skip_while(|arg| arg.as_str() != name);
+
+    // --name
+    args.next();
+    args.next().cloned()
+}
+
+fn has_flag(argv: &[String], name: &str) -> bool {
+    argv.iter().any(|arg| arg == name)
+}
+
+// a scratch directory for tests, deleted on drop so a failing assert leaves
+// nothing behind
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("http-server-{name}-{}", std::process::id()));
+        // left over by a run that was killed
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    pub(crate) fn join(&self, name: &str) -> std::path::PathBuf {
+        self.0.join(name)
+    }
+
+    // parent directories included
+    pub(crate) fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        let path = self.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(path, contents).unwrap();
+    }
+
+    // serving this directory
+    pub(crate) fn config(&self) -> Config {
+        Config {
+            directory: self.0.to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Config {
+        let argv = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        Config::parse(&argv, None)
+    }
+
+    #[test]
+    fn public_bind_with_writes_needs_confirmation() {
+        assert!(parse(&["server", "--bind", "0.0.0.0"])
+            .check_public_writes()
+            .is_err());
+        assert!(parse(&["server", "--bind", "::"])
+            .check_public_writes()
+            .is_err());
+        assert!(parse(&["server", "--bind", "0.0.0.0", "--read-only"])
+            .check_public_writes()
+            .is_ok());
+        assert!(
+            parse(&["server", "--bind", "0.0.0.0", "--allow-public-writes"])
+                .check_public_writes()
+                .is_ok()
+        );
+        assert!(parse(&["server"]).check_public_writes().is_ok());
+
+        let embedded = Config {
+            embedded: true,
+            ..parse(&["server", "--bind", "0.0.0.0"])
+        };
+        assert!(!embedded.accepts_uploads());
+        assert!(embedded.check_public_writes().is_ok());
+    }
+
+    #[test]
+    fn directory_comes_from_arg_then_env_then_cwd() {
+        let argv = ["server", "--directory", "/from/arg"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>();
+        let config = Config::parse(&argv, Some("/from/env".into()));
+        assert_eq!(config.directory, "/from/arg");
+
+        let argv = vec!["server".to_string()];
+        let config = Config::parse(&argv, Some("/from/env".into()));
+        assert_eq!(config.directory, "/from/env");
+
+        let cwd = env::current_dir().unwrap().to_string_lossy().into_owned();
+        assert_eq!(Config::parse(&argv, None).directory, cwd);
+        // set but empty is the same as unset
+        assert_eq!(Config::parse(&argv, Some(String::new())).directory, cwd);
+    }
+
+    #[test]
+    fn reload_rereads_the_headers_file() {
+        let dir = TestDir::new("headers");
+        let path = dir.join("headers");
+        fs::write(&path, "# injected\nX-Version: 1\n").unwrap();
+        let argv = ["server", "--headers-file", path.to_str().unwrap()]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>();
+        let live = LiveConfig::new(Config::load(&argv).unwrap(), argv);
+
+        let before = live.get();
+        assert_eq!(before.extra_headers, [("X-Version".into(), "1".into())]);
+
+        fs::write(&path, "X-Version: 2\n").unwrap();
+        live.reload();
+        assert_eq!(live.get().extra_headers, [("X-Version".into(), "2".into())]);
+        // taken before the reload, still the old one
+        assert_eq!(before.extra_headers, [("X-Version".into(), "1".into())]);
+
+        // a broken file keeps what was there
+        fs::write(&path, "no colon\n").unwrap();
+        live.reload();
+        assert_eq!(live.get().extra_headers, [("X-Version".into(), "2".into())]);
+    }
+
+    #[test]
+    fn bad_upload_modes_and_fds_are_refused() {
+        let load = |args: &[&str]| {
+            let argv = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            Config::load(&argv)
+        };
+        for mode in ["0999", "rw-r--r--", "17777", ""] {
+            let error = load(&["server", "--upload-mode", mode]).unwrap_err();
+            assert!(error.contains("--upload-mode"), "{error}");
+        }
+        assert_eq!(
+            load(&["server", "--upload-mode", "0o640"])
+                .unwrap()
+                .upload_mode,
+            Some(0o640)
+        );
+
+        for fd in ["three", "-1", "3.0"] {
+            let error = load(&["server", "--listen-fd", fd]).unwrap_err();
+            assert!(error.contains("--listen-fd"), "{error}");
+        }
+        assert_eq!(
+            load(&["server", "--listen-fd", "3"]).unwrap().listen_fd,
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn every_valued_flag_refuses_what_it_cannot_parse() {
+        for (flag, _, _) in VALUED_FLAGS {
+            let argv = ["server", flag, "many"].map(String::from);
+            let error = Config::load(&argv).unwrap_err();
+            assert!(
+                error.starts_with(&format!("invalid {flag} \"many\"")),
+                "{error}"
+            );
+        }
+
+        let load = |args: &[&str]| {
+            let argv = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            Config::load(&argv)
+        };
+        assert!(load(&["server", "--port", "65536"]).is_err());
+        assert!(load(&["server", "--max-queue", "-1"]).is_err());
+        let config = load(&[
+            "server",
+            "--max-queue",
+            "8",
+            "--keep-alive-timeout",
+            "0",
+            "--bind",
+            "::1",
+        ])
+        .unwrap();
+        assert_eq!(config.max_queue, Some(8));
+        assert_eq!(config.keep_alive_timeout, None);
+        assert_eq!(config.bind, IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]));
+    }
+}
@@ -0,0 +1,16 @@
+// files compiled into the binary with the `embed` feature, served under /files/ when
+// no directory is configured
+
+pub(crate) struct EmbeddedFile {
+    // relative to the embedded directory, `/` separated
+    pub name: &'static str,
+    pub content: &'static [u8],
+    // quoted, from a hash of the content taken at build time
+    pub etag: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+pub(crate) fn find<'a>(files: &'a [EmbeddedFile], name: &str) -> Option<&'a EmbeddedFile> {
+    files.iter().find(|file| file.name == name)
+}
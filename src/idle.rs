@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log;
+use crate::transport::Closer;
+
+// the open connections across all listeners, for picking the ones kept alive the
+// longest when too many wait for their next request, or closing the ones left waiting
+// too long even when their worker is stuck in a read that never times out (half-open
+// sockets)
+pub(crate) fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    connections: Mutex<HashMap<u64, Entry>>,
+    next_id: AtomicU64,
+}
+
+struct Entry {
+    closer: Box<dyn Closer>,
+    // `None` while a request is being handled
+    idle_since: Option<Instant>,
+    // answered a request already, waiting means being kept alive
+    kept_alive: bool,
+    // over the idle limit: the next response closes the connection
+    close_requested: bool,
+}
+
+// deregisters on drop
+pub(crate) struct Registration<'a> {
+    registry: &'a Registry,
+    id: u64,
+}
+
+impl Registry {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Entry>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn register(&self, closer: Box<dyn Closer>) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            closer,
+            idle_since: Some(Instant::now()),
+            kept_alive: false,
+            close_requested: false,
+        };
+        self.lock().insert(id, entry);
+        Registration { registry: self, id }
+    }
+
+    // closes the connections idle for longer than `threshold`, how many there were
+    pub(crate) fn reap(&self, threshold: Duration) -> usize {
+        let mut connections = self.lock();
+        let idle = connections
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .idle_since
+                    .is_some_and(|since| since.elapsed() > threshold)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u64>>();
+        for id in &idle {
+            if let Some(entry) = connections.remove(id) {
+                // already gone on the client side is just as good
+                let _ = entry.closer.close();
+            }
+        }
+        idle.len()
+    }
+
+    // asks the connections kept alive the longest, all but the `max` most recent, to
+    // close with their next response, how many were asked. Closing the socket instead
+    // would lose a request already on its way; only those asked and still silent
+    // after `grace` (the keep-alive timeout) are closed outright.
+    pub(crate) fn limit_idle(&self, max: usize, grace: Option<Duration>) -> usize {
+        let mut connections = self.lock();
+        let silent = connections
+            .iter()
+            .filter(|(_, entry)| entry.close_requested)
+            .filter(|(_, entry)| {
+                entry
+                    .idle_since
+                    .zip(grace)
+                    .is_some_and(|(since, grace)| since.elapsed() > grace)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u64>>();
+        for id in &silent {
+            if let Some(entry) = connections.remove(id) {
+                let _ = entry.closer.close();
+            }
+        }
+
+        let mut idle = connections
+            .iter()
+            .filter(|(_, entry)| entry.kept_alive && !entry.close_requested)
+            .filter_map(|(id, entry)| entry.idle_since.map(|since| (since, *id)))
+            .collect::<Vec<(Instant, u64)>>();
+        let excess = idle.len().saturating_sub(max);
+        idle.sort_unstable();
+        for (_, id) in &idle[..excess] {
+            if let Some(entry) = connections.get_mut(id) {
+                entry.close_requested = true;
+            }
+        }
+        excess
+    }
+}
+
+impl Registration<'_> {
+    fn set_idle(&self, idle: bool) {
+        if let Some(entry) = self.registry.lock().get_mut(&self.id) {
+            entry.idle_since = idle.then(Instant::now);
+            entry.kept_alive |= !idle;
+        }
+    }
+
+    // waiting for the next request
+    pub(crate) fn idle(&self) {
+        self.set_idle(true);
+    }
+
+    pub(crate) fn busy(&self) {
+        self.set_idle(false);
+    }
+
+    // picked by `Registry::limit_idle`, this connection ends with its response
+    pub(crate) fn close_requested(&self) -> bool {
+        self.registry
+            .lock()
+            .get(&self.id)
+            .is_some_and(|entry| entry.close_requested)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.id);
+    }
+}
+
+pub(crate) fn spawn_reaper(threshold: Duration) {
+    // a connection lives at most half a period past the threshold
+    let period = (threshold / 2).max(Duration::from_millis(100));
+    thread::spawn(move || loop {
+        thread::sleep(period);
+        let reaped = registry().reap(threshold);
+        if reaped > 0 {
+            log::debug(&format!("reaped {reaped} idle connections"));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let registry = Registry::default();
+        let registration = registry.register(Box::new(stream.try_clone().unwrap()));
+        registration.busy();
+        assert_eq!(registry.reap(Duration::ZERO), 0);
+
+        registration.idle();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(registry.reap(Duration::from_secs(60)), 0);
+        assert_eq!(registry.reap(Duration::from_millis(5)), 1);
+
+        // the blocked read a worker would be in returns
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        drop(registration);
+        assert!(registry.lock().is_empty());
+    }
+
+    #[test]
+    fn the_longest_kept_alive_connections_are_asked_to_close_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let registry = Registry::default();
+        let mut connections = (0..4)
+            .map(|_| {
+                let client = TcpStream::connect(address).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+                let registration = registry.register(Box::new(stream.try_clone().unwrap()));
+                (client, stream, registration)
+            })
+            .collect::<Vec<_>>();
+
+        // the first one never had a request, the second is busy with one
+        connections[1].2.busy();
+        for (_, _, registration) in &connections[2..] {
+            registration.busy();
+            thread::sleep(Duration::from_millis(5));
+            registration.idle();
+        }
+        assert_eq!(registry.limit_idle(2, None), 0);
+        assert_eq!(registry.limit_idle(1, None), 1);
+        assert!(connections[2].2.close_requested());
+        assert!(!connections[3].2.close_requested());
+        // asked once is enough, and nothing is closed under a request on its way
+        assert_eq!(registry.limit_idle(1, None), 0);
+        assert_eq!(registry.lock().len(), 4);
+
+        // still silent after the grace period
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(registry.limit_idle(1, Some(Duration::from_secs(60))), 0);
+        assert_eq!(registry.lock().len(), 4);
+        assert_eq!(registry.limit_idle(1, Some(Duration::from_millis(5))), 0);
+        let mut buf = [0; 1];
+        assert_eq!(connections[2].1.read(&mut buf).unwrap(), 0);
+        assert_eq!(registry.lock().len(), 3);
+        assert!(registry.lock().contains_key(&connections[3].2.id));
+        connections.clear();
+        assert!(registry.lock().is_empty());
+    }
+}
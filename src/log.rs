@@ -0,0 +1,163 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::request::Request;
+use crate::signal;
+use crate::Response;
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+struct AccessLog {
+    path: PathBuf,
+    output: Mutex<Output>,
+}
+
+struct Output {
+    writer: BufWriter<File>,
+    // reopen the file whenever a SIGHUP happened since the last write (log rotation)
+    hangups: usize,
+}
+
+fn open_log(path: &PathBuf) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+impl AccessLog {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let writer = open_log(&path)?;
+        Ok(AccessLog {
+            path,
+            output: Mutex::new(Output {
+                writer,
+                hangups: signal::hangups(),
+            }),
+        })
+    }
+
+    fn append(&self, line: &str) {
+        let Ok(mut output) = self.output.lock() else {
+            return;
+        };
+
+        let hangups = signal::hangups();
+        if output.hangups != hangups {
+            output.hangups = hangups;
+            match open_log(&self.path) {
+                Ok(writer) => output.writer = writer,
+                Err(e) => error(&format!(
+                    "cannot reopen access log {}: {e}",
+                    self.path.display()
+                )),
+            }
+        }
+
+        if let Err(e) = writeln!(output.writer, "{line}").and_then(|()| output.writer.flush()) {
+            error(&format!("cannot write access log: {e}"));
+        }
+    }
+}
+
+pub(crate) fn init_access_log(path: &str) -> io::Result<()> {
+    let access_log = AccessLog::open(PathBuf::from(path))?;
+    // first initialisation wins
+    let _ = ACCESS_LOG.set(access_log);
+    Ok(())
+}
+
+fn access_line(request: &Request, response: &Response) -> String {
+    let peer = request
+        .peer
+        .map_or_else(|| "-".to_string(), |peer| peer.ip().to_string());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    format!(
+        "{peer} [{timestamp}] \"{} {} {}\" {} {}",
+        request.method,
+        request.path,
+        request.version,
+        response.status.code(),
+        response.content_len()
+    )
+}
+
+pub(crate) fn access(request: &Request, response: &Response) {
+    let line = access_line(request, response);
+    match ACCESS_LOG.get() {
+        Some(access_log) => access_log.append(&line),
+        None => println!("{line}"),
+    }
+}
+
+pub(crate) fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub(crate) fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+pub(crate) fn error(message: &str) {
+    eprintln!("{message}");
+}
+
+pub(crate) fn debug(message: &str) {
+    if is_verbose() {
+        eprintln!("{message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+    use crate::request::RequestBuilder;
+    use crate::HttpCode;
+
+    #[test]
+    fn requests_are_appended_to_the_access_log() {
+        let dir = TestDir::new("access-log");
+        let path = dir.join("access.log");
+        std::fs::write(&path, "earlier\n").unwrap();
+        let access_log = AccessLog::open(path.clone()).unwrap();
+
+        let mut request = RequestBuilder::new().path("/echo/abc").build();
+        request.peer = Some("192.0.2.7:51000".parse().unwrap());
+        let response = Response {
+            content: b"abc".to_vec(),
+            ..Default::default()
+        };
+        access_log.append(&access_line(&request, &response));
+        request.peer = None;
+        let response = Response {
+            status: HttpCode::NotFound,
+            ..Default::default()
+        };
+        access_log.append(&access_line(&request, &response));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{log}");
+        assert_eq!(lines[0], "earlier");
+        for (line, peer, tail) in [
+            (lines[1], "192.0.2.7", "] \"GET /echo/abc HTTP/1.1\" 200 3"),
+            (lines[2], "-", "] \"GET /echo/abc HTTP/1.1\" 404 0"),
+        ] {
+            let timestamp = line
+                .strip_prefix(&format!("{peer} ["))
+                .and_then(|rest| rest.strip_suffix(tail))
+                .unwrap_or_else(|| panic!("{line}"));
+            assert!(timestamp.parse::<u64>().is_ok(), "{line}");
+        }
+    }
+}
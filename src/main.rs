@@ -1,38 +1,76 @@
+mod compress;
+mod config;
+mod embedded;
+mod idle;
+mod log;
+mod metrics;
 mod middleware;
+mod mime;
+mod negotiation;
+mod pool;
 mod request;
 mod route;
+mod signal;
+mod stream;
+mod transport;
 
 use anyhow::Result;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Instant;
 use std::{
-    env::{self},
-    io::Write,
+    io::{self, BufRead, BufReader, Read, Write},
     thread,
 };
 
-use flate2::write::{DeflateEncoder, GzEncoder};
-use flate2::Compression;
-
-use request::Request;
+use config::{Config, LiveConfig};
+use pool::ThreadPool;
+use request::{Request, RequestError};
+use stream::StreamedBody;
+use transport::{Deadline, Transport};
 
 #[derive(Debug)]
 enum HttpCode {
     Ok,
     NotFound,
+    MethodNotAllowed,
     InternalServerError,
     BadRequest,
+    RequestTimeout,
+    UriTooLong,
+    NotAcceptable,
     Created,
+    NoContent,
+    MovedPermanently,
+    TemporaryRedirect,
+    NotModified,
+    PreconditionFailed,
+    PartialContent,
+    RangeNotSatisfiable,
+    RequestHeaderFieldsTooLarge,
+    NotImplemented,
+    ServiceUnavailable,
+    // any other code, for /status/{code}
+    Other(u16),
 }
 
 #[derive(Debug)]
 struct Response {
     status: HttpCode,
     version: String,
-    content_type: String,
+    // `None` sends text/plain, or nothing when there is no body
+    content_type: Option<String>,
     content_encoding: Option<String>,
     connection: Option<String>,
+    headers: Vec<(String, String)>,
+    // a byte range of the identity representation, must not be re-encoded
+    partial: bool,
     content: Vec<u8>,
+    // sent instead of `content`, for bodies too big to hold in memory
+    stream: Option<StreamedBody>,
+    // answering HEAD: the headers a GET would get, without the body
+    head_only: bool,
 }
 
 impl Default for Response {
@@ -40,37 +78,190 @@ impl Default for Response {
         Response {
             status: HttpCode::Ok,
             version: "HTTP/1.1".into(),
-            content_type: "text/plain".to_string(),
+            content_type: None,
             content_encoding: None,
             connection: None,
+            headers: Vec::new(),
+            partial: false,
             content: Vec::new(),
+            stream: None,
+            head_only: false,
+        }
+    }
+}
+
+impl HttpCode {
+    fn code(&self) -> u16 {
+        match self {
+            HttpCode::Ok => 200,
+            HttpCode::BadRequest => 400,
+            HttpCode::RequestTimeout => 408,
+            HttpCode::UriTooLong => 414,
+            HttpCode::NotAcceptable => 406,
+            HttpCode::NotFound => 404,
+            HttpCode::MethodNotAllowed => 405,
+            HttpCode::InternalServerError => 500,
+            HttpCode::Created => 201,
+            HttpCode::NoContent => 204,
+            HttpCode::MovedPermanently => 301,
+            HttpCode::TemporaryRedirect => 307,
+            HttpCode::NotModified => 304,
+            HttpCode::PreconditionFailed => 412,
+            HttpCode::PartialContent => 206,
+            HttpCode::RangeNotSatisfiable => 416,
+            HttpCode::RequestHeaderFieldsTooLarge => 431,
+            HttpCode::NotImplemented => 501,
+            HttpCode::ServiceUnavailable => 503,
+            HttpCode::Other(code) => *code,
+        }
+    }
+
+    // the named variant when there is one, `Other` for the rest
+    fn from_code(code: u16) -> Self {
+        match code {
+            200 => HttpCode::Ok,
+            201 => HttpCode::Created,
+            204 => HttpCode::NoContent,
+            206 => HttpCode::PartialContent,
+            301 => HttpCode::MovedPermanently,
+            304 => HttpCode::NotModified,
+            307 => HttpCode::TemporaryRedirect,
+            400 => HttpCode::BadRequest,
+            404 => HttpCode::NotFound,
+            405 => HttpCode::MethodNotAllowed,
+            406 => HttpCode::NotAcceptable,
+            408 => HttpCode::RequestTimeout,
+            412 => HttpCode::PreconditionFailed,
+            414 => HttpCode::UriTooLong,
+            416 => HttpCode::RangeNotSatisfiable,
+            431 => HttpCode::RequestHeaderFieldsTooLarge,
+            500 => HttpCode::InternalServerError,
+            501 => HttpCode::NotImplemented,
+            503 => HttpCode::ServiceUnavailable,
+            code => HttpCode::Other(code),
         }
     }
+
+    // 1xx and 204 have no body and a 304 describes the cached one, none gets body headers
+    fn has_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+}
+
+impl Display for HttpCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let code = self.code();
+        write!(f, "{code} {}", reason_phrase(code))
+    }
+}
+
+// empty for codes without a registered one, the status line stays valid
+fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "I'm a teapot",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
 }
 
 impl Response {
+    // the Content-Type sent, an empty body only has one when it was set
+    fn content_type(&self) -> Option<&str> {
+        match &self.content_type {
+            Some(content_type) => Some(content_type),
+            None if self.content.is_empty() && self.stream.is_none() => None,
+            None => Some("text/plain"),
+        }
+    }
+
+    // of the identity representation when streamed
+    fn content_len(&self) -> u64 {
+        self.stream
+            .as_ref()
+            .map_or(self.content.len() as u64, StreamedBody::len)
+    }
+
     fn compress(self, compression: Option<&str>) -> Self {
         match compression {
             Some(compression) => {
-                let algorithm = match compression {
-                    // order matters
-                    c if c.contains("gzip") => Some("gzip"),
-                    c if c.contains("deflate") => Some("deflate"),
-                    _ => None,
-                };
+                // order matters: preferred first when q-values tie
+                let algorithm = negotiation::best_token(compression, &["gzip", "deflate"]);
+                if self.stream.is_some() {
+                    // compressed on the way out, see `StreamedBody::write_to`
+                    return Response {
+                        content_encoding: algorithm.map(std::string::ToString::to_string),
+                        ..self
+                    };
+                }
 
                 let compressed_content = match algorithm {
-                    Some("gzip") => compress_gzip(&self.content),
-                    Some("deflate") => compress_deflate(&self.content),
+                    Some("gzip") => compress::gzip(&self.content),
+                    Some("deflate") => compress::deflate(&self.content),
                     _ => Ok(self.content.clone()),
                 };
 
                 match compressed_content {
-                    Ok(compressed_content) => Response {
-                        content_encoding: algorithm.map(std::string::ToString::to_string),
-                        content: compressed_content,
-                        ..self
-                    },
+                    Ok(compressed_content) => {
+                        if let Some(algorithm) = algorithm.filter(|_| log::is_verbose()) {
+                            log::debug(&compression_summary(
+                                algorithm,
+                                self.content.len(),
+                                compressed_content.len(),
+                            ));
+                        }
+                        Response {
+                            content_encoding: algorithm.map(std::string::ToString::to_string),
+                            content: compressed_content,
+                            ..self
+                        }
+                    }
                     Err(e) => Response {
                         status: HttpCode::InternalServerError,
                         content: format!("Error compressing content: {e}").into(),
@@ -84,44 +275,304 @@ impl Response {
 }
 
 fn main() {
-    println!("Logs from your program will appear here! => http://127.0.0.1:4221");
+    let argv = std::env::args().collect::<Vec<String>>();
+    let config = Config::load(&argv).unwrap_or_else(|e| {
+        log::error(&e);
+        std::process::exit(1);
+    });
+    let live = Arc::new(LiveConfig::new(config, argv));
+    let config = live.get();
+    let address = SocketAddr::new(config.bind, config.port);
+    println!("Logs from your program will appear here! => http://{address}");
 
-    let destination_directory = extract_destination_directory_from_args();
-    println!("dest dir: {destination_directory}");
+    if config.is_public() {
+        log::error(&format!(
+            "WARNING: listening on {} makes the server reachable from the network",
+            config.bind
+        ));
+    }
+    if let Err(e) = config.check_public_writes() {
+        log::error(&e);
+        std::process::exit(1);
+    }
+
+    println!("dest dir: {}", config.directory);
+    if !config.keep_alive {
+        println!("keep-alive disabled");
+    }
+    if let Some(delay) = config.inject_delay {
+        println!(
+            "latency injection enabled: {}ms by default",
+            delay.as_millis()
+        );
+    }
 
-    let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
+    log::set_verbose(config.verbose);
+    if config.hsts_max_age.is_some() && !config.is_https() {
+        // browsers ignore the header over plain http, sending it only misleads
+        log::error("warning: --hsts ignored, it needs TLS in front of the server (--scheme https)");
+    }
+    if cfg!(not(unix)) && config.upload_mode.is_some() {
+        log::error("warning: --upload-mode ignored, file modes are a unix thing");
+    }
+    signal::install_sighup_handler();
+    if let Some(access_log) = &config.access_log {
+        if let Err(e) = log::init_access_log(access_log) {
+            log::error(&format!("cannot open access log {access_log}: {e}"));
+            std::process::exit(1);
+        }
+        println!("access log: {access_log}");
+    }
 
-    for stream in listener.incoming() {
-        let dest_dir = destination_directory.clone();
+    if let Some(threshold) = config.reap_idle {
+        idle::spawn_reaper(threshold);
+    }
+
+    let pool = Arc::new(ThreadPool::new(
+        config.min_threads,
+        config.max_threads,
+        config.idle_timeout,
+        &config.thread_name,
+    ));
+
+    if config.redirect_port.is_some() && !config.is_https() {
+        // the redirect would send clients to an https port nobody answers on
+        log::error("warning: --redirect-port ignored, it needs TLS in front of the server (--scheme https)");
+    }
+    if let Some(port) = config.redirect_port.filter(|_| config.is_https()) {
+        let redirect_address = SocketAddr::new(config.bind, port);
+        let listener = TcpListener::bind(redirect_address).unwrap_or_else(|e| {
+            log::error(&format!("cannot listen on redirect port {port}: {e}"));
+            std::process::exit(1);
+        });
+        println!("redirecting http://{redirect_address} to https");
+        let config = Arc::new(live.redirecting());
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || serve(listener.incoming(), &pool, &config));
+    }
+
+    #[cfg(unix)]
+    if let Some(socket_path) = config.bind_unix.clone() {
+        let listener = bind_unix(&socket_path).unwrap_or_else(|e| {
+            log::error(&format!("cannot listen on {socket_path}: {e}"));
+            std::process::exit(1);
+        });
+        println!("listening on unix socket {socket_path}");
+        let config = Arc::clone(&live);
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || serve(listener.incoming(), &pool, &config));
+    }
+
+    let listener = match config.listen_fd {
+        Some(fd) => adopt_listener(fd).unwrap_or_else(|e| {
+            log::error(&format!("cannot listen on fd {fd}: {e}"));
+            std::process::exit(1);
+        }),
+        None => TcpListener::bind(address).unwrap(),
+    };
+    serve(listener.incoming(), &pool, &live);
+}
+
+// takes over a socket bound and listened on by whoever started us
+#[cfg(unix)]
+fn adopt_listener(fd: i32) -> io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    #[cfg(target_os = "linux")]
+    const SOL_SOCKET: i32 = 1;
+    #[cfg(target_os = "linux")]
+    const SO_ACCEPTCONN: i32 = 30;
+    #[cfg(not(target_os = "linux"))]
+    const SOL_SOCKET: i32 = 0xffff;
+    #[cfg(not(target_os = "linux"))]
+    const SO_ACCEPTCONN: i32 = 0x0002;
+
+    extern "C" {
+        fn getsockopt(fd: i32, level: i32, name: i32, value: *mut i32, len: *mut u32) -> i32;
+    }
+
+    let mut accepting = 0;
+    let mut len = std::mem::size_of::<i32>() as u32;
+    if unsafe { getsockopt(fd, SOL_SOCKET, SO_ACCEPTCONN, &mut accepting, &mut len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if accepting == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a listening socket",
+        ));
+    }
+
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // a unix socket listens too, but it has no address a TcpListener understands
+    listener.local_addr()?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn adopt_listener(_fd: i32) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--listen-fd needs a unix system",
+    ))
+}
+
+#[cfg(unix)]
+fn bind_unix(socket_path: &str) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // a socket left over by a previous run would make bind fail
+    if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(socket_path)?;
+        }
+    }
+
+    let listener = std::os::unix::net::UnixListener::bind(socket_path)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+fn serve<S>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    pool: &ThreadPool,
+    config: &Arc<LiveConfig>,
+) where
+    S: Transport + Send + 'static,
+{
+    for stream in incoming {
+        let config = Arc::clone(config);
         match stream {
             Ok(mut stream) => {
-                println!("accepted new connection");
-                thread::spawn(move || match handle_connection(&mut stream, &dest_dir) {
+                log::debug("accepted new connection");
+                if config
+                    .get()
+                    .max_queue
+                    .is_some_and(|max| pool.queued() >= max)
+                {
+                    log::error("request queue full, turning a connection away");
+                    // off the accept thread, the request has to be drained first
+                    thread::spawn(move || {
+                        if let Err(e) = reject_busy(&mut stream) {
+                            log::debug(&format!("cannot answer the busy connection: {e}"));
+                        }
+                    });
+                    continue;
+                }
+                pool.execute(move || match handle_connection(&mut stream, &config) {
                     Ok(()) => {}
+                    Err(e) if is_disconnect(&e) => {
+                        log::debug(&format!("client went away: {e}"));
+                    }
                     Err(e) => {
-                        println!("connection error: {e}");
+                        log::error(&format!("connection error: {e}"));
                     }
                 });
             }
             Err(e) => {
-                println!("error: {e}");
+                log::error(&format!("error: {e}"));
             }
         }
     }
 }
 
-fn handle_connection(stream: &mut TcpStream, dest_dir: &str) -> Result<()> {
+// the client closing its end (even halfway through a response) is business as usual
+fn is_disconnect(error: &anyhow::Error) -> bool {
+    let io_error = match error.downcast_ref::<RequestError>() {
+        Some(RequestError::ConnectionClosed) => return true,
+        Some(RequestError::IoErr(e)) => Some(e),
+        _ => error.downcast_ref::<io::Error>(),
+    };
+
+    io_error.is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    })
+}
+
+fn handle_connection<S: Transport>(stream: &mut S, live: &LiveConfig) -> Result<()> {
+    let peer = stream.peer();
+    let registration = Some(live.get())
+        .filter(|config| config.reap_idle.is_some() || config.max_idle_connections.is_some())
+        .and_then(|_| stream.closer().ok())
+        .map(|closer| idle::registry().register(closer));
+    // one for the whole connection: what it read past a request is pipelined data,
+    // the start of the next one
+    let mut reader = BufReader::new(Deadline::new(stream));
     loop {
-        let Some(request) = request::parse_request(stream)? else {
-            continue;
+        // a reload (SIGHUP) applies from the next request on
+        let config = live.get();
+        if let Some(registration) = &registration {
+            registration.idle();
+            // too many kept alive: the ones waiting the longest make room
+            if let Some(max) = config.max_idle_connections {
+                let asked = idle::registry().limit_idle(max, config.keep_alive_timeout);
+                if asked > 0 {
+                    log::debug(&format!(
+                        "idle connection limit reached, closing {asked} after their next response"
+                    ));
+                }
+            }
+        }
+        // the header clock starts with the first byte, waiting for it is the idle
+        // time: bounded too, a silent client would hold its worker forever
+        reader.get_mut().start(config.keep_alive_timeout);
+        match reader.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                log::debug("keep-alive timeout, closing connection");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        reader.get_mut().clear()?;
+        if let Some(registration) = &registration {
+            registration.busy();
+        }
+        reader.get_mut().start(config.header_timeout);
+        let parsed = request::parse_request(&mut reader, &config);
+        reader.get_mut().clear()?;
+        let mut request = match parsed {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => return reject(reader.get_mut().stream(), e),
         };
-        println!("parsed request: {request:?}");
+        request.peer = peer;
+        if config.max_idle_connections == Some(0)
+            || registration
+                .as_ref()
+                .is_some_and(idle::Registration::close_requested)
+        {
+            // no connection may wait for another request, or this one is over the limit
+            request.persistent = false;
+        }
+        log::debug(&format!("parsed request: {request:?}"));
 
-        let bytes = handle_http_message(&request, dest_dir);
-        stream.write_all(&bytes)?;
+        let mut body = request::body_reader(&mut reader, &request);
+        let response = if route::streams_body(&request) {
+            let response = handle_http_message(&request, &mut body, &config);
+            // what the handler left unread must not be taken for the next request
+            io::copy(&mut body, &mut io::sink())?;
+            response
+        } else {
+            body.read_to_end(&mut request.body)?;
+            handle_http_message(&request, &mut io::empty(), &config)
+        };
+        handle_response(response, reader.get_mut().stream())?;
 
-        if !request.is_persistent() {
-            println!("closing connection");
+        if !config.keep_alive || !request.is_persistent() {
+            log::debug("closing connection");
             break;
         }
     }
@@ -129,67 +580,937 @@ fn handle_connection(stream: &mut TcpStream, dest_dir: &str) -> Result<()> {
     Ok(())
 }
 
-fn handle_http_message(request: &Request, dest_dir: &str) -> Vec<u8> {
-    let response = route::handle_request(request, dest_dir);
-    let response = middleware::handle_middlewares(request, response);
-    handle_response(response)
-}
+// answer a request that could not be parsed, then give up on the connection
+fn reject<S: Transport>(stream: &mut S, error: anyhow::Error) -> Result<()> {
+    let Some(status) = error
+        .downcast_ref::<RequestError>()
+        .and_then(RequestError::status)
+    else {
+        return Err(error);
+    };
 
-fn handle_response(response: Response) -> Vec<u8> {
-    let content = response.content;
-    let head = match response.status {
-        HttpCode::Ok => "200 OK",
-        HttpCode::BadRequest => "400 Bad Request",
-        HttpCode::NotFound => "404 Not Found",
-        HttpCode::InternalServerError => "500 Internal Server Error",
-        HttpCode::Created => "201 Created",
+    log::error(&format!("rejected request: {error}"));
+    let response = Response {
+        status,
+        connection: Some("close".into()),
+        content: error.to_string().into_bytes(),
+        ..Default::default()
     };
+    handle_response(response, stream)?;
+    Ok(())
+}
 
-    let content_type = response.content_type;
+// answered from the accepting thread, without waiting for a worker
+fn reject_busy<S: Transport>(stream: &mut S) -> io::Result<()> {
+    let response = Response {
+        status: HttpCode::ServiceUnavailable,
+        connection: Some("close".into()),
+        headers: vec![("Retry-After".into(), "1".into())],
+        content: b"Server busy".to_vec(),
+        ..Default::default()
+    };
+    handle_response(response, stream)?;
+    linger(stream)
+}
 
-    let mut raw_response: Vec<Vec<u8>> = Vec::new();
-    raw_response.push(format!("{} {head}\r\n", response.version).into());
+// how long, and how much, a connection we are done with may still send
+const LINGER_TIME: std::time::Duration = std::time::Duration::from_secs(1);
+const LINGER_BYTES: u64 = 64 * 1024;
 
-    if let Some(connection) = response.connection {
-        raw_response.push(format!("Connection: {connection}\r\n").into());
+// closing with the request still unread makes the kernel answer with a reset, and
+// the client may drop the response it had not read yet along with the connection:
+// half-close, then read until the client closes its side too
+fn linger<S: Transport>(stream: &mut S) -> io::Result<()> {
+    stream.shutdown_write()?;
+    let mut deadline = Deadline::new(stream);
+    deadline.start(Some(LINGER_TIME));
+    match io::copy(&mut deadline.take(LINGER_BYTES), &mut io::sink()) {
+        Err(e)
+            if !matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Err(e)
+        }
+        _ => Ok(()),
     }
+}
 
-    match content.len() {
-        0 => {
-            raw_response.push("\r\n".into());
-        }
-        n => {
-            raw_response.push(format!("Content-Type: {content_type}\r\n").into());
-            raw_response.push(format!("Content-Length: {n}\r\n").into());
-            if let Some(compression) = response.content_encoding {
-                raw_response.push(format!("Content-Encoding: {compression}\r\n").into());
-            }
-            raw_response.push("\r\n".into());
-            raw_response.push(content);
+// `body` is only readable for requests the route streams, see `route::streams_body`
+fn handle_http_message(request: &Request, body: &mut dyn Read, config: &Config) -> Response {
+    let started = Instant::now();
+    let (response, middlewares) = route::handle_request(request, body, config);
+    let response = middleware::handle_middlewares(request, response, middlewares, config);
+    metrics::record_request(started.elapsed());
+    log::access(request, &response);
+    response
+}
+
+fn handle_response(mut response: Response, out: &mut dyn Write) -> io::Result<()> {
+    let Some(body) = response
+        .stream
+        .take()
+        .filter(|_| response.status.has_body())
+    else {
+        return out.write_all(&serialize(response));
+    };
+
+    let mut head = status_and_headers(&response);
+    let content_type = response.content_type.as_deref().unwrap_or("text/plain");
+    head.push_str(&format!("Content-Type: {content_type}\r\n"));
+    match &response.content_encoding {
+        Some(compression) => {
+            head.push_str(&format!("Content-Encoding: {compression}\r\n"));
+            head.push_str("Transfer-Encoding: chunked\r\n");
         }
+        None => head.push_str(&format!("Content-Length: {}\r\n", body.len())),
     }
-    raw_response.concat()
+    head.push_str("\r\n");
+    out.write_all(head.as_bytes())?;
+    if response.head_only {
+        return Ok(());
+    }
+    body.write_to(response.content_encoding.as_deref(), out)
 }
 
-fn extract_destination_directory_from_args() -> String {
-    let argv = env::args().collect::<Vec<String>>();
-    let mut search_dir = argv
-        .into_iter()
-        .skip_while(|arg| arg.as_str() != "--directory");
+fn status_and_headers(response: &Response) -> String {
+    let mut head = format!("{} {}\r\n", response.version, response.status);
+    if let Some(connection) = &response.connection {
+        head.push_str(&format!("Connection: {connection}\r\n"));
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head
+}
+
+fn serialize(response: Response) -> Vec<u8> {
+    let mut head = status_and_headers(&response);
+    if !response.status.has_body() {
+        head.push_str("\r\n");
+        return head.into_bytes();
+    }
 
-    // --directory
-    search_dir.next();
-    search_dir.next().unwrap_or("/tmp/".into())
+    if let Some(content_type) = response.content_type() {
+        head.push_str(&format!("Content-Type: {content_type}\r\n"));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.content.len()));
+    if let Some(compression) = response
+        .content_encoding
+        .filter(|_| !response.content.is_empty())
+    {
+        head.push_str(&format!("Content-Encoding: {compression}\r\n"));
+    }
+    head.push_str("\r\n");
+    let mut raw_response = head.into_bytes();
+    if !response.head_only {
+        raw_response.extend_from_slice(&response.content);
+    }
+    raw_response
 }
 
-fn compress_gzip(content: &[u8]) -> Result<Vec<u8>> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(content)?;
-    encoder.finish().map_err(anyhow::Error::from)
+// e.g. `gzip: 1200 -> 300 bytes (25.0%)`
+fn compression_summary(algorithm: &str, before: usize, after: usize) -> String {
+    let ratio = if before == 0 {
+        100.0
+    } else {
+        after as f64 * 100.0 / before as f64
+    };
+    format!("{algorithm}: {before} -> {after} bytes ({ratio:.1}%)")
 }
 
-fn compress_deflate(content: &[u8]) -> Result<Vec<u8>> {
-    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(content)?;
-    encoder.finish().map_err(anyhow::Error::from)
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use config::TestDir;
+    use request::RequestBuilder;
+
+    // the bytes sent back for `request`
+    fn wire(request: &Request, config: &Config) -> Vec<u8> {
+        let response = handle_http_message(request, &mut io::empty(), config);
+        let mut out = Vec::new();
+        handle_response(response, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn large_files_stream_gzip_chunked() {
+        let dir = TestDir::new("stream");
+        let content = (0..stream::STREAM_THRESHOLD / 16)
+            .map(|line| format!("line {line:>10}\n"))
+            .collect::<String>();
+        dir.write("big.txt", &content);
+        let config = dir.config();
+
+        let request = RequestBuilder::new()
+            .path("/files/big.txt")
+            .header("Accept-Encoding", "gzip")
+            .build();
+        let raw = wire(&request, &config);
+        let split = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of head");
+        let head = String::from_utf8(raw[..split + 2].to_vec()).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        let chunked = RequestBuilder::new()
+            .header("Transfer-Encoding", "chunked")
+            .build();
+        let mut compressed = Vec::new();
+        request::body_reader(&raw[split + 4..], &chunked)
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert!(compressed.len() < content.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded == content);
+
+        // without compression the size is known up front
+        let request = RequestBuilder::new().path("/files/big.txt").build();
+        let raw = wire(&request, &config);
+        let head = String::from_utf8_lossy(&raw[..200]).into_owned();
+        assert!(head.contains(&format!("Content-Length: {}\r\n", content.len())));
+        assert!(raw.ends_with(b"line      65535\n"));
+    }
+
+    #[test]
+    fn compressed_echo_content_length_matches_body() {
+        let request = RequestBuilder::new()
+            .path("/echo/hello-hello-hello")
+            .header("Accept-Encoding", "gzip")
+            .build();
+
+        let raw = wire(&request, &Config::default());
+
+        let split = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of head");
+        let head = String::from_utf8(raw[..split].to_vec()).unwrap();
+        let body = &raw[split + 4..];
+
+        assert!(head.contains("Content-Encoding: gzip"));
+        let content_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .expect("Content-Length header")
+            .parse::<usize>()
+            .unwrap();
+        assert_eq!(content_length, body.len());
+
+        let mut decoded = String::new();
+        GzDecoder::new(body).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "hello-hello-hello");
+    }
+
+    #[test]
+    fn separate_accept_encoding_lines_are_combined() {
+        let raw = b"GET /echo/hello-hello-hello HTTP/1.1\r\n\
+                    Accept-Encoding: br\r\n\
+                    Accept-Encoding: gzip\r\n\r\n";
+        let request = request::parse_request(&mut &raw[..], &Config::default())
+            .unwrap()
+            .unwrap();
+
+        let raw = wire(&request, &Config::default());
+        let split = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of head");
+        let head = String::from_utf8(raw[..split].to_vec()).unwrap();
+        assert!(head.contains("Content-Encoding: gzip"), "{head}");
+        let mut decoded = String::new();
+        GzDecoder::new(&raw[split + 4..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello-hello-hello");
+    }
+
+    #[test]
+    fn head_of_a_streamed_file_sends_no_body() {
+        let dir = TestDir::new("head-stream");
+        let content = vec![b'x'; stream::STREAM_THRESHOLD as usize];
+        dir.write("big.bin", &content);
+        let request = RequestBuilder::new()
+            .method("HEAD")
+            .path("/files/big.bin")
+            .build();
+
+        let raw = String::from_utf8(wire(&request, &dir.config())).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{raw}");
+        assert!(raw.contains(&format!("Content-Length: {}\r\n", content.len())));
+        assert!(raw.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn ranges_of_large_files_are_streamed() {
+        let dir = TestDir::new("range-stream");
+        let content = (0..2 * stream::STREAM_THRESHOLD)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        dir.write("big.bin", &content);
+        let config = dir.config();
+        let range = |spec: &str| {
+            RequestBuilder::new()
+                .path("/files/big.bin")
+                .header("Range", spec)
+                .header("Accept-Encoding", "gzip")
+                .build()
+        };
+
+        let request = range("bytes=1000000-1000999");
+        let (response, _) = route::handle_request(&request, &mut io::empty(), &config);
+        assert!(response.stream.is_some());
+        assert!(response.content.is_empty());
+
+        let raw = wire(&request, &config);
+        let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(raw[..split + 2].to_vec()).unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{head}"
+        );
+        assert!(head.contains(&format!(
+            "Content-Range: bytes 1000000-1000999/{}\r\n",
+            content.len()
+        )));
+        assert!(head.contains("Content-Length: 1000\r\n"), "{head}");
+        assert!(!head.contains("Content-Encoding"), "{head}");
+        assert_eq!(&raw[split + 4..], &content[1_000_000..1_001_000]);
+
+        let raw = wire(&range("bytes=-10"), &config);
+        assert!(raw.ends_with(&content[content.len() - 10..]));
+
+        let raw = String::from_utf8(wire(&range("bytes=99999999-"), &config)).unwrap();
+        assert!(
+            raw.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"),
+            "{raw}"
+        );
+        assert!(raw.contains(&format!("Content-Range: bytes */{}\r\n", content.len())));
+    }
+
+    #[test]
+    fn empty_files_keep_their_content_type() {
+        let dir = TestDir::new("empty-type");
+        dir.write("blank.png", "");
+        let request = RequestBuilder::new()
+            .path("/files/blank.png")
+            .header("Accept-Charset", "utf-8;q=0")
+            .build();
+
+        let raw = String::from_utf8(wire(&request, &dir.config())).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{raw}");
+        assert!(raw.contains("\r\nContent-Type: image/png\r\n"), "{raw}");
+        assert!(raw.ends_with("Content-Length: 0\r\n\r\n"), "{raw}");
+    }
+
+    #[test]
+    fn empty_root_is_never_compressed() {
+        let request = RequestBuilder::new()
+            .path("/")
+            .header("Accept-Encoding", "gzip, deflate")
+            .build();
+
+        let raw = String::from_utf8(wire(&request, &Config::default())).unwrap();
+
+        assert!(!raw.contains("Content-Encoding"));
+        assert!(raw.ends_with("Content-Length: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn user_agent_is_compressed_when_accepted() {
+        let request = RequestBuilder::new()
+            .path("/user-agent")
+            .header("User-Agent", "foobar/1.2.3")
+            .header("Accept-Encoding", "gzip")
+            .build();
+
+        let raw = wire(&request, &Config::default());
+        let split = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of head");
+        let head = String::from_utf8(raw[..split].to_vec()).unwrap();
+        assert!(head.contains("Content-Encoding: gzip"));
+        let mut decoded = String::new();
+        GzDecoder::new(&raw[split + 4..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "foobar/1.2.3");
+
+        // without Accept-Encoding it goes out as is
+        let request = RequestBuilder::new()
+            .path("/user-agent")
+            .header("User-Agent", "foobar/1.2.3")
+            .build();
+        let raw = String::from_utf8(wire(&request, &Config::default())).unwrap();
+        assert!(!raw.contains("Content-Encoding"));
+        assert!(raw.ends_with("\r\n\r\nfoobar/1.2.3"));
+    }
+
+    #[test]
+    fn empty_file_has_zero_length_and_type() {
+        let dir = TestDir::new("empty");
+        dir.write("empty.txt", b"");
+        let config = dir.config();
+
+        let request = RequestBuilder::new().path("/files/empty.txt").build();
+        let raw = wire(&request, &config);
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(raw.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(raw.ends_with("Content-Length: 0\r\n\r\n"));
+
+        let request = RequestBuilder::new()
+            .path("/files/empty.txt")
+            .header("Range", "bytes=0-")
+            .build();
+        let raw = wire(&request, &config);
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(raw.contains("Content-Range: bytes */0\r\n"));
+    }
+
+    #[test]
+    fn metrics_opts_out_of_compression() {
+        let request = RequestBuilder::new()
+            .path("/metrics")
+            .header("Accept-Encoding", "gzip")
+            .build();
+        let (response, middlewares) =
+            route::handle_request(&request, &mut io::empty(), &Config::default());
+        assert!(!middlewares.compress);
+
+        let response =
+            middleware::handle_middlewares(&request, response, middlewares, &Config::default());
+        assert_eq!(response.content_encoding, None);
+        assert!(String::from_utf8(response.content)
+            .unwrap()
+            .contains("http_request_duration"));
+    }
+
+    #[test]
+    fn no_content_never_has_a_body() {
+        let response = Response {
+            status: HttpCode::NoContent,
+            content_type: Some("application/json".into()),
+            content: b"{}".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(serialize(response), b"HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn codes_map_to_their_named_variant() {
+        for code in 100..=599 {
+            assert_eq!(HttpCode::from_code(code).code(), code);
+        }
+        assert!(matches!(HttpCode::from_code(204), HttpCode::NoContent));
+        assert!(matches!(HttpCode::from_code(418), HttpCode::Other(418)));
+    }
+
+    #[test]
+    fn options_has_a_zero_content_length() {
+        for path in ["*", "/files/x"] {
+            let request = RequestBuilder::new()
+                .method("OPTIONS")
+                .path(path)
+                .header("Accept-Encoding", "gzip")
+                .build();
+            let raw = String::from_utf8(wire(&request, &Config::default())).unwrap();
+            assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{raw}");
+            assert!(raw.contains("\r\nAllow: GET, HEAD, POST, OPTIONS"));
+            assert!(!raw.contains("Content-Type"));
+            assert!(!raw.contains("Content-Encoding"));
+            assert!(raw.ends_with("\r\nContent-Length: 0\r\n\r\n"));
+        }
+    }
+
+    #[test]
+    fn trickled_headers_hit_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nX-Slow: ").unwrap();
+            // one byte at a time, long past the deadline unless cut off
+            for _ in 0..40 {
+                if client.write_all(b"a").is_err() {
+                    break;
+                }
+                thread::sleep(std::time::Duration::from_millis(25));
+            }
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            response
+        });
+
+        let config = Config {
+            header_timeout: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (mut stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_connection(&mut stream, &LiveConfig::fixed(config)).unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(900));
+        drop(stream);
+
+        let response = client.join().unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn no_keepalive_closes_after_one_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        // a second request is already there, it must not be answered
+        client
+            .write_all(b"GET /echo/one HTTP/1.1\r\n\r\nGET /echo/two HTTP/1.1\r\n\r\n")
+            .unwrap();
+
+        let config = LiveConfig::fixed(Config {
+            keep_alive: false,
+            ..Default::default()
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        handle_connection(&mut stream, &config).unwrap();
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\none"), "{response}");
+    }
+
+    #[test]
+    fn the_longest_idle_connection_closes_with_its_next_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = LiveConfig::fixed(Config {
+            max_idle_connections: Some(1),
+            ..Default::default()
+        });
+        let exchange = |client: &mut std::net::TcpStream, word: &str| {
+            write!(client, "GET /echo/{word} HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0; 1024];
+            while !response.ends_with(format!("\r\n\r\n{word}").as_bytes()) {
+                let n = client.read(&mut buf).unwrap();
+                assert!(n > 0, "closed early");
+                response.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8(response).unwrap()
+        };
+
+        let config = &config;
+        thread::scope(|scope| {
+            let mut first = std::net::TcpStream::connect(address).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            scope.spawn(move || handle_connection(&mut stream, config).unwrap());
+            let mut second = std::net::TcpStream::connect(address).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            scope.spawn(move || handle_connection(&mut stream, config).unwrap());
+
+            exchange(&mut first, "one");
+            // for its worker to be back waiting
+            thread::sleep(std::time::Duration::from_millis(50));
+            exchange(&mut second, "two");
+            thread::sleep(std::time::Duration::from_millis(50));
+
+            // the one idle for longer makes room for the other, its request still
+            // gets an answer
+            let response = exchange(&mut first, "three");
+            assert!(response.contains("\r\nConnection: close\r\n"), "{response}");
+            let mut buf = [0; 1];
+            assert_eq!(first.read(&mut buf).unwrap(), 0);
+            let response = exchange(&mut second, "four");
+            assert!(!response.contains("Connection: close"), "{response}");
+            drop(second);
+        });
+    }
+
+    #[test]
+    fn idle_connections_give_their_worker_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = LiveConfig::fixed(Config {
+            keep_alive_timeout: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        });
+
+        // connects and never says anything
+        let silent = std::net::TcpStream::connect(address).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_connection(&mut stream, &config).unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(900));
+        drop(silent);
+
+        // one request, then kept alive with nothing more
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /echo/a HTTP/1.1\r\n\r\n").unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_connection(&mut stream, &config).unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(900));
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\na"), "{response}");
+    }
+
+    #[test]
+    fn pooled_encoder_round_trips_and_resets() {
+        let first = "first response ".repeat(100);
+        let second = "and a different second one";
+
+        // same thread, same encoder: what the first left behind must not leak
+        for content in [first.as_str(), second, first.as_str()] {
+            let gzip = compress::gzip(content.as_bytes()).unwrap();
+            let mut decoded = String::new();
+            GzDecoder::new(&gzip[..])
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, content);
+
+            let deflate = compress::deflate(content.as_bytes()).unwrap();
+            let mut decoded = String::new();
+            flate2::read::DeflateDecoder::new(&deflate[..])
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, content);
+        }
+    }
+
+    #[test]
+    fn compression_summary_reports_sizes() {
+        let content = "a".repeat(1000);
+        let compressed = compress::gzip(content.as_bytes()).unwrap();
+        let summary = compression_summary("gzip", content.len(), compressed.len());
+        assert_eq!(
+            summary,
+            format!(
+                "gzip: 1000 -> {} bytes ({:.1}%)",
+                compressed.len(),
+                compressed.len() as f64 / 10.0
+            )
+        );
+        assert_eq!(
+            compression_summary("deflate", 0, 0),
+            "deflate: 0 -> 0 bytes (100.0%)"
+        );
+    }
+
+    // a client that hangs up after `left` bytes
+    struct HangsUp {
+        left: usize,
+    }
+
+    impl Write for HangsUp {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.left);
+            self.left -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streaming_stops_when_the_client_hangs_up() {
+        use std::io::Seek;
+
+        let dir = TestDir::new("hang-up");
+        let len = 4 * stream::STREAM_THRESHOLD;
+        // noise, so that compressed it is still well past what the client takes
+        let mut state = 1u32;
+        let noise = (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 24) as u8
+            })
+            .collect::<Vec<_>>();
+        dir.write("big.bin", noise);
+
+        for encoding in [None, Some("gzip")] {
+            let file = std::fs::File::open(dir.join("big.bin")).unwrap();
+            // shares the offset, shows how far the file was read
+            let mut position = file.try_clone().unwrap();
+            let response = Response {
+                content_encoding: encoding.map(String::from),
+                stream: Some(stream::StreamedBody::new(file, len)),
+                ..Default::default()
+            };
+
+            let error = handle_response(response, &mut HangsUp { left: 64 * 1024 }).unwrap_err();
+            assert!(is_disconnect(&error.into()), "{encoding:?}");
+            let read = position.stream_position().unwrap();
+            assert!(read < len / 4, "{encoding:?}: read {read} of {len}");
+        }
+    }
+
+    #[test]
+    fn pipelined_requests_are_all_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client
+                .write_all(
+                    b"GET /echo/one HTTP/1.1\r\n\r\n\
+                      POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\ntwo\
+                      GET /echo/three HTTP/1.1\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        handle_connection(&mut stream, &LiveConfig::fixed(Config::default())).unwrap();
+        drop(stream);
+
+        let response = client.join().unwrap();
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            3,
+            "{response}"
+        );
+        let bodies = response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|message| message.split_once("\r\n\r\n").unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn full_queue_turns_connections_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let pool = Arc::new(ThreadPool::new(
+            1,
+            1,
+            std::time::Duration::from_secs(1),
+            "queue",
+        ));
+        // the only worker is busy until told otherwise
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = released.recv();
+        });
+        let config = Arc::new(LiveConfig::fixed(Config {
+            max_queue: Some(1),
+            ..Default::default()
+        }));
+        let serving = Arc::clone(&pool);
+        thread::spawn(move || serve(listener.incoming(), &serving, &config));
+
+        let mut queued = std::net::TcpStream::connect(address).unwrap();
+        queued
+            .write_all(b"GET /echo/queued HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        while pool.queued() == 0 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // a request still unread on close would reset the connection under the 503
+        let mut busy = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            busy,
+            "POST /echo HTTP/1.1\r\nContent-Length: 32768\r\n\r\n{}",
+            "x".repeat(32768)
+        )
+        .unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Retry-After: 1\r\n"));
+        assert!(response.ends_with("\r\n\r\nServer busy"), "{response}");
+
+        release.send(()).unwrap();
+        let mut response = String::new();
+        queued.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nqueued"), "{response}");
+        // other tests share the gauges, only the high-water mark is certain
+        let metrics = metrics::render();
+        assert!(metrics.contains("\nhttp_queue_depth "), "{metrics}");
+        assert!(!metrics.contains("\nhttp_queue_depth_max 0\n"), "{metrics}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_over_a_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixStream;
+
+        let dir = TestDir::new("unix");
+        let socket_path = dir.join("server.sock");
+        let socket_path = socket_path.to_str().unwrap();
+        // a stale socket from an earlier run is replaced
+        drop(bind_unix(socket_path).unwrap());
+        let listener = bind_unix(socket_path).unwrap();
+        let mode = std::fs::metadata(socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let mut client = UnixStream::connect(socket_path).unwrap();
+        client
+            .write_all(
+                b"GET /echo/unix HTTP/1.1\r\n\r\nGET /ip HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        handle_connection(&mut stream, &LiveConfig::fixed(Config::default())).unwrap();
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let (echo, ip) = response.split_once("HTTP/1.1 404").expect("two responses");
+        assert!(echo.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(echo.ends_with("\r\n\r\nunix"), "{response}");
+        // no IP peer on a unix socket
+        assert!(
+            ip.ends_with("No peer address for this connection"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn head_gets_the_get_headers_without_a_body() {
+        let get = RequestBuilder::new().path("/echo/abc").build();
+        let head = RequestBuilder::new()
+            .method("HEAD")
+            .path("/echo/abc")
+            .build();
+        let config = Config::default();
+
+        let get = String::from_utf8(wire(&get, &config)).unwrap();
+        let head = String::from_utf8(wire(&head, &config)).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Length: 3\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+        // the request id aside, the same head
+        let without_id = |raw: &str| {
+            raw.lines()
+                .filter(|line| !line.starts_with("X-Request-Id"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assert_eq!(
+            without_id(&head),
+            without_id(get.strip_suffix("abc").unwrap())
+        );
+    }
+
+    #[test]
+    fn hang_ups_are_disconnects() {
+        for kind in [
+            io::ErrorKind::BrokenPipe,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::UnexpectedEof,
+        ] {
+            assert!(is_disconnect(&io::Error::from(kind).into()), "{kind:?}");
+            let wrapped = RequestError::IoErr(io::Error::from(kind));
+            assert!(is_disconnect(&wrapped.into()), "{kind:?}");
+        }
+        assert!(is_disconnect(&RequestError::ConnectionClosed.into()));
+
+        assert!(!is_disconnect(
+            &io::Error::from(io::ErrorKind::PermissionDenied).into()
+        ));
+        assert!(!is_disconnect(&RequestError::InvalidHeader.into()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_on_an_inherited_listener() {
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(adopt_listener(udp.as_raw_fd()).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = adopt_listener(listener.into_raw_fd()).unwrap();
+        thread::spawn(move || {
+            let pool = ThreadPool::new(1, 1, std::time::Duration::from_secs(1), "listen-fd");
+            serve(
+                listener.incoming(),
+                &pool,
+                &Arc::new(LiveConfig::fixed(Config::default())),
+            );
+        });
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /echo/inherited HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ninherited"));
+    }
+
+    #[test]
+    fn redirect_listener_follows_reloads() {
+        let dir = TestDir::new("redirect");
+        let path = dir.join("headers");
+        std::fs::write(&path, "X-Version: 1\n").unwrap();
+        let argv = ["server", "--scheme", "https", "--headers-file"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain([path.to_str().unwrap().to_string()])
+            .collect::<Vec<_>>();
+        let live = LiveConfig::new(Config::load(&argv).unwrap(), argv);
+        let redirect = live.redirecting();
+        assert!(!live.get().https_redirect);
+
+        let request = RequestBuilder::new()
+            .path("/echo/abc?x=1")
+            .header("Host", "example.com:8080")
+            .build();
+        let head = String::from_utf8(wire(&request, &redirect.get())).unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("\r\nLocation: https://example.com/echo/abc?x=1\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nX-Version: 1\r\n"), "{head}");
+
+        std::fs::write(&path, "X-Version: 2\n").unwrap();
+        redirect.reload();
+        let head = String::from_utf8(wire(&request, &redirect.get())).unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nX-Version: 2\r\n"), "{head}");
+    }
 }
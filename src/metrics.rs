@@ -0,0 +1,104 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// upper bounds of the latency histogram buckets, in milliseconds
+const BUCKETS_MS: [u64; 9] = [1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+// one counter per bucket plus the +Inf one, not cumulative (prometheus wants
+// cumulative counts, they are summed up when rendering)
+static BUCKET_COUNTS: [AtomicU64; BUCKETS_MS.len() + 1] =
+    [const { AtomicU64::new(0) }; BUCKETS_MS.len() + 1];
+static DURATION_SUM_US: AtomicU64 = AtomicU64::new(0);
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+// connections waiting for a worker, now and at most since the start
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+static QUEUE_DEPTH_MAX: AtomicU64 = AtomicU64::new(0);
+
+fn bucket_index(duration: Duration) -> usize {
+    let micros = duration.as_micros();
+    BUCKETS_MS
+        .iter()
+        .position(|bound| micros <= u128::from(*bound) * 1000)
+        .unwrap_or(BUCKETS_MS.len())
+}
+
+pub(crate) fn record_request(duration: Duration) {
+    BUCKET_COUNTS[bucket_index(duration)].fetch_add(1, Ordering::Relaxed);
+    let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+    DURATION_SUM_US.fetch_add(micros, Ordering::Relaxed);
+    REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_queue_depth(depth: usize) {
+    let depth = u64::try_from(depth).unwrap_or(u64::MAX);
+    QUEUE_DEPTH.store(depth, Ordering::Relaxed);
+    QUEUE_DEPTH_MAX.fetch_max(depth, Ordering::Relaxed);
+}
+
+// prometheus text exposition format
+pub(crate) fn render() -> String {
+    let name = "http_request_duration_milliseconds";
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP {name} Time spent handling a request.");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+
+    let mut cumulative = 0;
+    for (i, count) in BUCKET_COUNTS.iter().enumerate() {
+        cumulative += count.load(Ordering::Relaxed);
+        let bound = BUCKETS_MS
+            .get(i)
+            .map_or_else(|| "+Inf".to_string(), ToString::to_string);
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+    }
+
+    let sum_ms = DURATION_SUM_US.load(Ordering::Relaxed) as f64 / 1000.0;
+    let _ = writeln!(out, "{name}_sum {sum_ms}");
+    let _ = writeln!(
+        out,
+        "{name}_count {}",
+        REQUEST_COUNT.load(Ordering::Relaxed)
+    );
+
+    for (name, help, value) in [
+        (
+            "http_queue_depth",
+            "Connections waiting for a worker.",
+            &QUEUE_DEPTH,
+        ),
+        (
+            "http_queue_depth_max",
+            "Most connections ever waiting for a worker.",
+            &QUEUE_DEPTH_MAX,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_land_in_their_bucket() {
+        // bounds are inclusive
+        assert_eq!(bucket_index(Duration::ZERO), 0);
+        assert_eq!(bucket_index(Duration::from_millis(1)), 0);
+        assert_eq!(bucket_index(Duration::from_micros(1001)), 1);
+        assert_eq!(bucket_index(Duration::from_millis(5)), 1);
+        assert_eq!(
+            bucket_index(Duration::from_millis(1000)),
+            BUCKETS_MS.len() - 1
+        );
+        // +Inf
+        assert_eq!(
+            bucket_index(Duration::from_micros(1_000_001)),
+            BUCKETS_MS.len()
+        );
+        assert_eq!(bucket_index(Duration::from_secs(60)), BUCKETS_MS.len());
+    }
+}
@@ -1,13 +1,100 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{Config, MAX_INJECTED_DELAY};
+use crate::log;
+use crate::negotiation;
 use crate::request::Request;
-use crate::Response;
+use crate::{HttpCode, Response};
+
+// the middlewares a route left enabled for its responses
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Middlewares {
+    pub compress: bool,
+}
+
+impl Default for Middlewares {
+    fn default() -> Self {
+        Middlewares { compress: true }
+    }
+}
 
-pub(crate) fn handle_middlewares(request: &Request, response: Response) -> Response {
+pub(crate) fn handle_middlewares(
+    request: &Request,
+    response: Response,
+    middlewares: Middlewares,
+    config: &Config,
+) -> Response {
+    let response = mw_delay(request, response, config);
+    let response = mw_accept_charset(request, response);
     let response = mw_version(request, response);
-    let response = mw_close_connection(request, response);
-    mw_compress(request, response)
+    let response = mw_close_connection(request, response, config);
+    let response = mw_hsts(response, config);
+    let response = mw_extra_headers(response, config);
+    let response = if middlewares.compress {
+        mw_compress(request, response)
+    } else {
+        response
+    };
+    // last, a failed compression is a 500 too
+    let response = mw_request_id(request, response);
+    mw_head(request, response)
+}
+
+fn mw_delay(request: &Request, response: Response, config: &Config) -> Response {
+    let Some(delay) = config.inject_delay else {
+        return response;
+    };
+
+    let delay = request
+        .query_param("delay")
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .map_or(delay, |ms| {
+            Duration::from_millis(ms).min(MAX_INJECTED_DELAY)
+        });
+    thread::sleep(delay);
+    response
+}
+
+// text is only ever sent in one charset, there is no transcoding
+fn mw_accept_charset(request: &Request, response: Response) -> Response {
+    let Some(accept) = request.headers.get_joined("accept-charset") else {
+        return response;
+    };
+    let content_type = response.content_type().unwrap_or_default();
+    let textual = content_type.starts_with("text/") || content_type.starts_with("application/json");
+    if accept.trim().is_empty() || !textual || response.status.code() != 200 {
+        return response;
+    }
+
+    let charset = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("charset="))
+        .unwrap_or("utf-8")
+        .to_string();
+    if negotiation::best_token(&accept, &[&charset]).is_some() {
+        return response;
+    }
+
+    Response {
+        status: HttpCode::NotAcceptable,
+        content_type: None,
+        headers: Vec::new(),
+        partial: false,
+        stream: None,
+        content: format!("Only available in {charset}").into_bytes(),
+        ..response
+    }
 }
 
-fn mw_close_connection(request: &Request, response: Response) -> Response {
+fn mw_close_connection(request: &Request, response: Response, config: &Config) -> Response {
+    if !config.keep_alive || !request.is_persistent() {
+        return Response {
+            connection: Some("close".into()),
+            ..response
+        };
+    }
+
     Response {
         connection: request
             .headers
@@ -17,6 +104,53 @@ fn mw_close_connection(request: &Request, response: Response) -> Response {
     }
 }
 
+fn mw_request_id(request: &Request, mut response: Response) -> Response {
+    if response.status.code() == 500 {
+        // the same id in the log and the body, so a user report can be correlated
+        log::error(&format!(
+            "[{}] {} {} failed: {}",
+            request.id,
+            request.method,
+            request.path,
+            String::from_utf8_lossy(&response.content)
+        ));
+        response
+            .content
+            .extend_from_slice(format!("\nrequest id: {}", request.id).as_bytes());
+    }
+
+    response
+        .headers
+        .push(("X-Request-Id".into(), request.id.clone()));
+    response
+}
+
+// HEAD is a GET whose body stays home, the headers (Content-Length included) are
+// those of the GET
+fn mw_head(request: &Request, response: Response) -> Response {
+    Response {
+        head_only: request.method == *"HEAD",
+        ..response
+    }
+}
+
+fn mw_hsts(mut response: Response, config: &Config) -> Response {
+    if let Some(max_age) = config.hsts_max_age.filter(|_| config.is_https()) {
+        response.headers.push((
+            "Strict-Transport-Security".into(),
+            format!("max-age={max_age}; includeSubDomains"),
+        ));
+    }
+    response
+}
+
+fn mw_extra_headers(mut response: Response, config: &Config) -> Response {
+    response
+        .headers
+        .extend(config.extra_headers.iter().cloned());
+    response
+}
+
 fn mw_version(request: &Request, response: Response) -> Response {
     Response {
         version: request.version.clone(),
@@ -25,10 +159,186 @@ fn mw_version(request: &Request, response: Response) -> Response {
 }
 
 fn mw_compress(request: &Request, response: Response) -> Response {
-    response.compress(
-        request
+    // the request id is appended to error bodies afterwards
+    if response.status.code() == 500 {
+        return response;
+    }
+    // ranges are computed on the identity content, compressing would break them
+    if response.partial {
+        return response;
+    }
+    // nothing to compress, an encoded empty body would only be bigger
+    if response.stream.is_none() && response.content.is_empty() {
+        return response;
+    }
+    // compressed streams are chunked, which HTTP/1.0 clients do not understand
+    if response.stream.is_some() && request.version != "HTTP/1.1" {
+        return response;
+    }
+
+    response.compress(request.headers.get_joined("accept-encoding").as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+    use crate::request::RequestBuilder;
+
+    fn text() -> Response {
+        Response {
+            content: b"hello".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn errors_carry_the_request_id_in_body_and_header() {
+        let request = RequestBuilder::new()
+            .header("Accept-Encoding", "gzip")
+            .build();
+        let failed = Response {
+            status: HttpCode::InternalServerError,
+            content: b"Error reading files".to_vec(),
+            ..Default::default()
+        };
+        let response =
+            handle_middlewares(&request, failed, Middlewares::default(), &Config::default());
+
+        let header = response
             .headers
-            .get("accept-encoding")
-            .map(std::string::String::as_str),
-    )
+            .iter()
+            .find(|(name, _)| name == "X-Request-Id")
+            .map(|(_, value)| value.as_str());
+        assert_eq!(header, Some(request.id.as_str()));
+        assert_eq!(response.content_encoding, None);
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            format!("Error reading files\nrequest id: {}", request.id)
+        );
+    }
+
+    #[test]
+    fn checks_go_by_the_code_not_the_variant() {
+        let request = RequestBuilder::new()
+            .header("Accept-Encoding", "gzip")
+            .header("Accept-Charset", "utf-8;q=0")
+            .build();
+        let failed = Response {
+            status: HttpCode::Other(500),
+            content: b"failed".to_vec(),
+            ..Default::default()
+        };
+        let response =
+            handle_middlewares(&request, failed, Middlewares::default(), &Config::default());
+        assert_eq!(response.content_encoding, None);
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            format!("failed\nrequest id: {}", request.id)
+        );
+
+        let ok = Response {
+            status: HttpCode::Other(200),
+            ..text()
+        };
+        assert!(matches!(
+            mw_accept_charset(&request, ok).status,
+            HttpCode::NotAcceptable
+        ));
+    }
+
+    #[test]
+    fn delay_query_only_applies_with_inject_delay() {
+        let request = RequestBuilder::new().path("/?delay=150").build();
+        let config = Config {
+            inject_delay: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        mw_delay(&request, Response::default(), &config);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        let request = RequestBuilder::new().path("/?delay=5000").build();
+        let started = std::time::Instant::now();
+        mw_delay(&request, Response::default(), &Config::default());
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn partial_content_is_never_compressed() {
+        let dir = TestDir::new("partial");
+        dir.write("hello.txt", b"hello-hello-hello");
+        let request = RequestBuilder::new()
+            .path("/files/hello.txt")
+            .header("Range", "bytes=0-4")
+            .header("Accept-Encoding", "gzip")
+            .build();
+        let config = dir.config();
+        let (response, middlewares) =
+            crate::route::handle_request(&request, &mut std::io::empty(), &config);
+        let response = handle_middlewares(&request, response, middlewares, &config);
+
+        assert!(matches!(response.status, HttpCode::PartialContent));
+        assert_eq!(response.content_encoding, None);
+        assert_eq!(response.content, b"hello");
+    }
+
+    #[test]
+    fn forbidden_utf8_is_not_acceptable() {
+        let request = RequestBuilder::new()
+            .header("Accept-Charset", "iso-8859-1, utf-8;q=0")
+            .build();
+        let response = mw_accept_charset(&request, text());
+        assert!(matches!(response.status, HttpCode::NotAcceptable));
+
+        let request = RequestBuilder::new()
+            .header("Accept-Charset", "iso-8859-1, *;q=0.1")
+            .build();
+        let response = mw_accept_charset(&request, text());
+        assert!(matches!(response.status, HttpCode::Ok));
+
+        // binary content has no charset to disagree about
+        let request = RequestBuilder::new()
+            .header("Accept-Charset", "utf-8;q=0")
+            .build();
+        let binary = Response {
+            content_type: Some("image/png".into()),
+            ..text()
+        };
+        assert!(matches!(
+            mw_accept_charset(&request, binary).status,
+            HttpCode::Ok
+        ));
+    }
+
+    #[test]
+    fn hsts_is_only_sent_over_https() {
+        let hsts = |response: &Response| {
+            response
+                .headers
+                .iter()
+                .find(|(name, _)| name == "Strict-Transport-Security")
+                .map(|(_, value)| value.clone())
+        };
+        let config = Config {
+            hsts_max_age: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(hsts(&mw_hsts(text(), &config)), None);
+
+        let config = Config {
+            scheme: "HTTPS".into(),
+            ..config
+        };
+        assert_eq!(
+            hsts(&mw_hsts(text(), &config)).as_deref(),
+            Some("max-age=600; includeSubDomains")
+        );
+
+        let config = Config {
+            hsts_max_age: None,
+            ..config
+        };
+        assert_eq!(hsts(&mw_hsts(text(), &config)), None);
+    }
 }
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+fn from_extension(extension: &str) -> Option<&'static str> {
+    let mime = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "js" | "mjs" => "application/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+// how much of a file is looked at to guess its type
+const SNIFF_LENGTH: u64 = 512;
+
+// a guess from the first bytes, for files without an extension
+fn from_content(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: [(&[u8], &str); 5] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+    ];
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| head.starts_with(signature))
+    {
+        return Some(mime);
+    }
+
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // a character cut in half by the end of the sample is still text
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if !text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace())
+    {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+fn sniff(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(SNIFF_LENGTH)
+        .read_to_end(&mut head)
+        .ok()?;
+    from_content(&head)
+}
+
+// types a charset parameter makes sense for
+fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/") || mime == "application/json" || mime == "application/javascript"
+}
+
+// `Content-Type` for a file served from disk, text types carry `charset`;
+// with `sniff`, a file without extension is typed from its first bytes
+pub(crate) fn content_type(path: &Path, charset: &str, sniff: bool) -> String {
+    let mime = match path.extension() {
+        Some(extension) => extension.to_str().and_then(from_extension),
+        None if sniff => self::sniff(path),
+        None => None,
+    }
+    .unwrap_or(DEFAULT_TYPE);
+
+    if is_text(mime) {
+        format!("{mime}; charset={charset}")
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_extensions_are_octet_streams() {
+        for name in ["archive.xyz", "notes.", "README"] {
+            assert_eq!(
+                content_type(Path::new(name), "utf-8", false),
+                "application/octet-stream"
+            );
+        }
+        assert_eq!(
+            content_type(Path::new("PAGE.HTM"), "utf-8", false),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn files_without_extension_are_sniffed() {
+        assert_eq!(from_content(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(from_content(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(
+            from_content(b"  <!DOCTYPE html><title>x</title>"),
+            Some("text/html")
+        );
+        assert_eq!(from_content(b"plain words\n"), Some("text/plain"));
+        // "é" cut in half by the end of the sample
+        assert_eq!(from_content(b"caf\xc3"), Some("text/plain"));
+        assert_eq!(from_content(b"\0\x01\x02"), None);
+        assert_eq!(from_content(b""), None);
+
+        // nothing to read, nothing to guess from
+        assert_eq!(
+            content_type(Path::new("/nonexistent/blob"), "utf-8", true),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn charset_goes_only_on_text_types() {
+        for (name, expected) in [
+            ("a.txt", "text/plain; charset=latin1"),
+            ("a.css", "text/css; charset=latin1"),
+            ("a.json", "application/json; charset=latin1"),
+            ("a.js", "application/javascript; charset=latin1"),
+            ("a.svg", "image/svg+xml"),
+            ("a.png", "image/png"),
+            ("a.pdf", "application/pdf"),
+            ("a.bin", "application/octet-stream"),
+        ] {
+            assert_eq!(content_type(Path::new(name), "latin1", false), expected);
+        }
+    }
+}
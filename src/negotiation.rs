@@ -0,0 +1,138 @@
+// content negotiation helpers for the `Accept-*` family of headers
+
+// splits `gzip;q=0.8, deflate` into (token, q) pairs, q defaults to 1
+pub(crate) fn parse_qvalues(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let token = params.next()?.trim();
+            if token.is_empty() {
+                return None;
+            }
+
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0)
+                .clamp(0.0, 1.0);
+            Some((token, q))
+        })
+        .collect()
+}
+
+// picks the supported token with the highest q-value, `supported` order breaks ties
+pub(crate) fn best_token<'a>(header: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let qvalues = parse_qvalues(header);
+    let wildcard = qvalues
+        .iter()
+        .find(|(token, _)| *token == "*")
+        .map(|(_, q)| *q);
+
+    let mut best: Option<(&'a str, f32)> = None;
+    for candidate in supported {
+        let q = qvalues
+            .iter()
+            .find(|(token, _)| token.eq_ignore_ascii_case(candidate))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+
+    best.map(|(token, _)| token)
+}
+
+// how specifically `range` (`*/*`, `type/*` or `type/subtype`) matches `mime`
+fn specificity(range: &str, mime: &str) -> Option<u8> {
+    let (range_type, range_subtype) = range.split_once('/')?;
+    let (mime_type, mime_subtype) = mime.split_once('/')?;
+    match (range_type, range_subtype) {
+        ("*", "*") => Some(0),
+        (t, "*") if t.eq_ignore_ascii_case(mime_type) => Some(1),
+        (t, s) if t.eq_ignore_ascii_case(mime_type) && s.eq_ignore_ascii_case(mime_subtype) => {
+            Some(2)
+        }
+        _ => None,
+    }
+}
+
+// picks the best of the `supported` media types for an `Accept` header: each type
+// takes the q-value of the most specific range matching it, `supported` order breaks
+// ties and no header means anything goes
+pub(crate) fn best_match<'a>(accept: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_qvalues(accept);
+    if ranges.is_empty() {
+        return supported.first().copied();
+    }
+
+    let mut best: Option<(&'a str, f32)> = None;
+    for candidate in supported {
+        let q = ranges
+            .iter()
+            .filter_map(|(range, q)| specificity(range, candidate).map(|s| (s, *q)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, q)| q);
+
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+
+    best.map(|(mime, _)| mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: &[&str] = &["text/plain", "application/json"];
+
+    #[test]
+    fn curl_takes_the_first_supported() {
+        assert_eq!(best_match("*/*", SUPPORTED), Some("text/plain"));
+        assert_eq!(best_match("", SUPPORTED), Some("text/plain"));
+    }
+
+    #[test]
+    fn q_values_decide() {
+        let accept = "application/json;q=0.9, text/plain;q=0.5, */*;q=0.1";
+        assert_eq!(best_match(accept, SUPPORTED), Some("application/json"));
+    }
+
+    #[test]
+    fn specific_ranges_win_over_wildcards() {
+        // text/plain is explicitly refused even though */* would accept it
+        assert_eq!(
+            best_match("text/plain;q=0, */*", SUPPORTED),
+            Some("application/json")
+        );
+        assert_eq!(
+            best_match("text/*;q=0.2, application/*;q=0.4", SUPPORTED),
+            Some("application/json")
+        );
+    }
+
+    #[test]
+    fn browser_accept_prefers_text() {
+        let firefox = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(best_match(firefox, SUPPORTED), Some("text/plain"));
+    }
+
+    #[test]
+    fn nothing_acceptable() {
+        assert_eq!(best_match("image/png", SUPPORTED), None);
+    }
+
+    #[test]
+    fn encodings_follow_q_values() {
+        assert_eq!(
+            best_token("gzip;q=0.1, deflate", &["gzip", "deflate"]),
+            Some("deflate")
+        );
+        assert_eq!(best_token("br", &["gzip", "deflate"]), None);
+    }
+}
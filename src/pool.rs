@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::log;
+use crate::metrics;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// an elastic pool: `min` workers are always around, up to `max` are spawned under
+// load and the extra ones exit after `idle_timeout` without work
+pub(crate) struct ThreadPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+    min: usize,
+    max: usize,
+    idle_timeout: Duration,
+    // workers are named `{name}-{index}`, for debuggers and profilers
+    name: String,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    workers: usize,
+    idle: usize,
+    // ever spawned, the next worker's index
+    spawned: usize,
+}
+
+impl ThreadPool {
+    pub(crate) fn new(min: usize, max: usize, idle_timeout: Duration, name: &str) -> Self {
+        let max = max.max(1);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                workers: 0,
+                idle: 0,
+                spawned: 0,
+            }),
+            available: Condvar::new(),
+            min: min.min(max),
+            max,
+            idle_timeout,
+            name: name.to_string(),
+        });
+
+        let pool = ThreadPool { shared };
+        for _ in 0..pool.shared.min {
+            let mut state = pool.shared.lock();
+            state.workers += 1;
+            drop(state);
+            pool.spawn_worker();
+        }
+        pool
+    }
+
+    pub(crate) fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.lock();
+        state.queue.push_back(Box::new(job));
+        metrics::record_queue_depth(state.queue.len());
+        let spawn = state.queue.len() > state.idle && state.workers < self.shared.max;
+        if spawn {
+            state.workers += 1;
+        }
+        drop(state);
+
+        if spawn {
+            self.spawn_worker();
+        }
+        self.shared.available.notify_one();
+    }
+
+    // jobs waiting for a worker
+    pub(crate) fn queued(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    fn spawn_worker(&self) {
+        let mut state = self.shared.lock();
+        let index = state.spawned;
+        state.spawned += 1;
+        drop(state);
+
+        let shared = Arc::clone(&self.shared);
+        let spawned = thread::Builder::new()
+            .name(format!("{}-{index}", self.shared.name))
+            .spawn(move || shared.work());
+        if let Err(e) = spawned {
+            // the remaining workers still drain the queue
+            log::error(&format!("failed to spawn a worker: {e}"));
+            self.shared.lock().workers -= 1;
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // jobs run outside of the lock, a poisoned state is still consistent
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn work(&self) {
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                metrics::record_queue_depth(state.queue.len());
+                drop(state);
+                // a panicking job must not take the worker (and its accounting) down
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                state = self.lock();
+                continue;
+            }
+
+            state.idle += 1;
+            let (next, wait) = self
+                .available
+                .wait_timeout(state, self.idle_timeout)
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            state = next;
+            state.idle -= 1;
+
+            if wait.timed_out() && state.queue.is_empty() && state.workers > self.min {
+                state.workers -= 1;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn workers_are_named() {
+        let pool = ThreadPool::new(1, 2, Duration::from_secs(1), "http-worker");
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || {
+            tx.send(thread::current().name().map(String::from)).unwrap();
+        });
+
+        let name = rx.recv().unwrap().expect("named worker");
+        assert!(name.starts_with("http-worker-"), "{name}");
+    }
+
+    #[test]
+    fn workers_scale_up_under_load_and_back_down() {
+        let pool = ThreadPool::new(1, 3, Duration::from_millis(100), "scale");
+        let (started, running) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        for _ in 0..4 {
+            let started = started.clone();
+            let released = Arc::clone(&released);
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+            });
+        }
+
+        // three at once, the fourth waits for a worker
+        for _ in 0..3 {
+            running.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+        assert_eq!(pool.shared.lock().workers, 3);
+        assert_eq!(pool.queued(), 1);
+
+        for _ in 0..4 {
+            release.send(()).unwrap();
+        }
+        running.recv_timeout(Duration::from_secs(1)).unwrap();
+        // the extra workers time out, `min` stay
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while pool.shared.lock().workers > 1 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.shared.lock().workers, 1);
+        assert_eq!(pool.queued(), 0);
+    }
+}
@@ -1,22 +1,28 @@
 use anyhow::Result;
 
 use std::{
-    collections::HashMap,
     fmt::{self, Display, Formatter},
-    io::{BufRead, BufReader, Read},
-    net::TcpStream,
+    io::{self, BufRead, Read, Take},
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use thiserror::Error;
 
+use crate::config::Config;
+use crate::HttpCode;
+
 #[derive(Debug)]
 pub(crate) struct Request {
     pub method: String,
     pub path: String,
+    pub query: String,
     pub version: String,
-    pub headers: HeadersHere,
+    pub headers: Headers,
     pub body: Vec<u8>,
     pub persistent: bool,
+    pub peer: Option<SocketAddr>,
+    pub id: String,
 }
 
 #[derive(Debug, Error)]
@@ -26,7 +32,17 @@ pub(crate) enum RequestError {
     MissingMethod,
     MissingPath,
     MissingVersion,
+    InvalidStartLine,
+    UriTooLong,
+    InvalidTarget,
+    WhitespaceInTarget,
     InvalidHeader,
+    InvalidUtf8,
+    TooManyHeaders,
+    HeaderLineTooLong,
+    HeaderTimeout,
+    UnsupportedTransferEncoding(String),
+    ChunkedNotLast,
 }
 
 impl Display for RequestError {
@@ -36,8 +52,61 @@ impl Display for RequestError {
             RequestError::MissingMethod => write!(f, "Missing method"),
             RequestError::MissingPath => write!(f, "Missing path"),
             RequestError::MissingVersion => write!(f, "Missing version"),
+            RequestError::InvalidStartLine => write!(f, "Malformed request line"),
+            RequestError::UriTooLong => write!(f, "Request target too long"),
+            RequestError::InvalidTarget => {
+                write!(f, "Request target must be an absolute path")
+            }
+            RequestError::WhitespaceInTarget => {
+                write!(
+                    f,
+                    "Request path must not decode to whitespace or control characters"
+                )
+            }
             RequestError::IoErr(e) => write!(f, "io error: {e}"),
             RequestError::InvalidHeader => write!(f, "Invalid header"),
+            RequestError::InvalidUtf8 => write!(f, "Request line is not valid UTF-8"),
+            RequestError::TooManyHeaders => write!(f, "Too many header fields"),
+            RequestError::HeaderLineTooLong => write!(f, "Header line too long"),
+            RequestError::HeaderTimeout => write!(f, "Request headers not received in time"),
+            RequestError::UnsupportedTransferEncoding(coding) => {
+                write!(f, "Unsupported transfer encoding: {coding}")
+            }
+            RequestError::ChunkedNotLast => {
+                write!(f, "Transfer-Encoding must end with chunked, applied once")
+            }
+        }
+    }
+}
+
+impl RequestError {
+    // a socket read timeout while reading the head is the header deadline passing
+    fn from_read(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RequestError::HeaderTimeout,
+            _ => RequestError::IoErr(error),
+        }
+    }
+
+    // the status to answer with, `None` when the client cannot be answered anymore
+    pub(crate) fn status(&self) -> Option<HttpCode> {
+        match self {
+            RequestError::IoErr(_) | RequestError::ConnectionClosed => None,
+            RequestError::MissingMethod
+            | RequestError::MissingPath
+            | RequestError::MissingVersion
+            | RequestError::InvalidStartLine
+            | RequestError::InvalidTarget
+            | RequestError::WhitespaceInTarget
+            | RequestError::InvalidHeader
+            | RequestError::InvalidUtf8 => Some(HttpCode::BadRequest),
+            RequestError::UriTooLong => Some(HttpCode::UriTooLong),
+            RequestError::TooManyHeaders | RequestError::HeaderLineTooLong => {
+                Some(HttpCode::RequestHeaderFieldsTooLarge)
+            }
+            RequestError::HeaderTimeout => Some(HttpCode::RequestTimeout),
+            RequestError::UnsupportedTransferEncoding(_) => Some(HttpCode::NotImplemented),
+            RequestError::ChunkedNotLast => Some(HttpCode::BadRequest),
         }
     }
 }
@@ -47,35 +116,151 @@ impl Request {
         method: &str,
         path: &str,
         version: &str,
-        headers: HeadersHere,
+        headers: Headers,
         body: Vec<u8>,
         persistent: bool,
     ) -> Self {
+        // the request target is `path[?query]`
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let id = headers
+            .get("x-request-id")
+            .filter(|id| is_valid_request_id(id))
+            .map_or_else(generate_request_id, ToString::to_string);
         Self {
             method: method.to_string(),
             path: path.to_string(),
+            query: query.to_string(),
             version: version.to_string(),
             headers,
             body,
             persistent,
+            peer: None,
+            id,
         }
     }
 
     pub(crate) fn is_persistent(&self) -> bool {
         self.persistent
     }
+
+    // the request target as it was sent: `path[?query]`
+    pub(crate) fn target(&self) -> String {
+        if self.query.is_empty() {
+            self.path.clone()
+        } else {
+            format!("{}?{}", self.path, self.query)
+        }
+    }
+
+    // first value of `name` in the query string, decoded
+    pub(crate) fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| {
+                String::from_utf8_lossy(&percent_decode(&value.replace('+', " "))).into_owned()
+            })
+    }
+}
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn generate_request_id() -> String {
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{count:08x}", std::process::id())
 }
 
-pub(crate) type HeadersHere = HashMap<String, String>;
+// ids coming from clients end up in logs and headers, keep them tame
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// `%XX` sequences are decoded, malformed ones are kept as-is
+pub(crate) fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+// header lines in arrival order, names compared case-insensitively
+#[derive(Debug, Default)]
+pub(crate) struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub(crate) fn insert(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
 
-pub(crate) fn parse_request(stream: &mut TcpStream) -> Result<Option<Request>> {
-    let mut buf_reader = BufReader::new(stream);
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 
+    pub(crate) fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // repeated list headers are equivalent to a single comma-joined one
+    pub(crate) fn get_joined(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name).collect::<Vec<&str>>();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+}
+
+// reads the start line and headers only, the body is left in `buf_reader`
+pub(crate) fn parse_request<R: BufRead>(
+    buf_reader: &mut R,
+    config: &Config,
+) -> Result<Option<Request>> {
     // get request specification: start line
-    let mut start_line_part = String::new();
-    let _size = buf_reader
-        .read_line(&mut start_line_part)
-        .map_err(RequestError::IoErr)?;
+    // read raw bytes: read_line would turn bad UTF-8 into an opaque io error
+    // room for the longest target allowed, plus a line for method and version
+    let mut start_line_bytes = Vec::new();
+    let start_line_cap = config.max_uri_length + config.max_line_length;
+    if !read_line_capped(buf_reader, &mut start_line_bytes, start_line_cap)
+        .map_err(RequestError::from_read)?
+    {
+        return Err(RequestError::UriTooLong.into());
+    }
+    let mut start_line_part =
+        String::from_utf8(start_line_bytes).map_err(|_| RequestError::InvalidUtf8)?;
 
     // empty buffer => connection closed
     if start_line_part.is_empty() {
@@ -88,60 +273,738 @@ pub(crate) fn parse_request(stream: &mut TcpStream) -> Result<Option<Request>> {
         return Ok(None);
     }
 
-    let mut start_line = start_line_part.split(' ');
+    // strict: exactly `method SP target SP version`, otherwise any run of whitespace
+    // separates the tokens
+    let tokens = if config.strict {
+        start_line_part.split(' ').collect::<Vec<&str>>()
+    } else {
+        start_line_part.split_whitespace().collect()
+    };
+    if config.strict
+        && (tokens.len() != 3
+            || tokens
+                .iter()
+                .any(|token| token.is_empty() || token.contains(char::is_whitespace)))
+    {
+        return Err(RequestError::InvalidStartLine.into());
+    }
+    let mut start_line = tokens.into_iter();
     let method = start_line.next().ok_or(RequestError::MissingMethod)?;
     let path = start_line.next().ok_or(RequestError::MissingPath)?;
     let version = start_line.next().ok_or(RequestError::MissingVersion)?;
 
-    // get headers
-    let mut headers: HeadersHere = HashMap::new();
-    let mut header_part = String::new();
-    while buf_reader
-        .read_line(&mut header_part)
-        .map_err(RequestError::IoErr)?
-        > 0
+    // too long is 414 whatever it looks like, only a target within the cap is checked
+    if path.len() > config.max_uri_length {
+        return Err(RequestError::UriTooLong.into());
+    }
+    let asterisk = path == "*" && method == "OPTIONS";
+    if !(path.starts_with('/') || asterisk) || path.contains(|c: char| c.is_control()) {
+        return Err(RequestError::InvalidTarget.into());
+    }
+    // `%20` or `%09` would turn back into the separators the request line is split on
+    let decoded_path = percent_decode(path.split_once('?').map_or(path, |(path, _)| path));
+    if decoded_path
+        .iter()
+        .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
     {
-        header_part = header_part.trim().into();
+        return Err(RequestError::WhitespaceInTarget.into());
+    }
+
+    // get headers
+    let mut headers = Headers::default();
+    let mut header_bytes = Vec::new();
+    loop {
+        header_bytes.clear();
+        if !read_line_capped(buf_reader, &mut header_bytes, config.max_line_length)
+            .map_err(RequestError::from_read)?
+        {
+            return Err(RequestError::HeaderLineTooLong.into());
+        }
+        if header_bytes.is_empty() {
+            break;
+        }
+        let header_part = std::str::from_utf8(&header_bytes)
+            .map_err(|_| RequestError::InvalidHeader)?
+            .trim();
         if header_part.is_empty() {
             break;
         }
 
-        let mut header_parts = header_part.split(": ");
-        let key = header_parts
-            .next()
-            .ok_or(RequestError::InvalidHeader)?
-            .to_string()
-            .to_lowercase();
-        let value = header_parts
-            .next()
-            .ok_or(RequestError::InvalidHeader)?
-            .to_string();
+        // bail out before doing any work for the extra header
+        if headers.len() >= config.max_header_count {
+            return Err(RequestError::TooManyHeaders.into());
+        }
+
+        // the name ends at the first colon, the value may itself hold ": "
+        let (key, value) = header_part
+            .split_once(':')
+            .ok_or(RequestError::InvalidHeader)?;
+        let value = value.trim_matches([' ', '\t']);
+        // a stray CR or NUL would let a client smuggle in what others read as headers
+        if !is_token(key) || !is_field_value(value) {
+            return Err(RequestError::InvalidHeader.into());
+        }
         headers.insert(key, value);
-        header_part.clear();
+    }
+
+    // a body in an unknown coding cannot be framed, refuse it rather than guess
+    if let Some(codings) = headers.get_joined("transfer-encoding") {
+        let unsupported = codings.split(',').map(str::trim).find(|coding| {
+            !coding.eq_ignore_ascii_case("chunked") && !coding.eq_ignore_ascii_case("identity")
+        });
+        if let Some(coding) = unsupported {
+            return Err(RequestError::UnsupportedTransferEncoding(coding.to_string()).into());
+        }
+        // without chunked last the body length is unknown (RFC 9112 6.3): falling back
+        // to Content-Length would frame it differently than a proxy in front might
+        let mut codings = codings.split(',').map(str::trim);
+        let chunked_once = codings
+            .clone()
+            .filter(|coding| coding.eq_ignore_ascii_case("chunked"))
+            .count()
+            == 1;
+        if !chunked_once
+            || !codings
+                .next_back()
+                .is_some_and(|last| last.eq_ignore_ascii_case("chunked"))
+        {
+            return Err(RequestError::ChunkedNotLast.into());
+        }
     }
 
     // get connection close state
     let connexion_close = matches!(headers.get("connection"), Some(v) if v == "close");
 
-    // get body with the right length
-    let content_length = headers
+    let persistent = version.contains("1.1") && !connexion_close;
+    let mut request = Request::new(method, path, version, headers, Vec::new(), persistent);
+    if config.merge_slashes {
+        request.path = merge_slashes(&request.path);
+    }
+    Ok(Some(request))
+}
+
+// `read_until(b'\n')` that stops at `max` bytes, `false` when the line is longer:
+// nothing past the cap is buffered
+fn read_line_capped<R: BufRead>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<bool> {
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Ok(true);
+        }
+        let (chunk, complete) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (&available[..=end], true),
+            None => (available, false),
+        };
+        if line.len() + chunk.len() > max {
+            return Ok(false);
+        }
+        line.extend_from_slice(chunk);
+        let consumed = chunk.len();
+        reader.consume(consumed);
+        if complete {
+            return Ok(true);
+        }
+    }
+}
+
+// `/files//sub///x` => `/files/sub/x`
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !merged.ends_with('/') {
+            merged.push(c);
+        }
+    }
+    merged
+}
+
+// one request from an in-memory buffer, body included, for the parser tests
+#[cfg(test)]
+pub(crate) fn parse_request_from_bytes(bytes: &[u8]) -> Result<Option<Request>> {
+    let mut reader = bytes;
+    let Some(mut request) = parse_request(&mut reader, &Config::default())? else {
+        return Ok(None);
+    };
+    body_reader(&mut reader, &request)
+        .read_to_end(&mut request.body)
+        .map_err(RequestError::IoErr)?;
+    Ok(Some(request))
+}
+
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// anything but control characters, tabs excepted
+fn is_field_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || !b.is_ascii_control())
+}
+
+// the message body that follows the head of `request`
+pub(crate) fn body_reader<R: BufRead>(reader: R, request: &Request) -> Body<R> {
+    let chunked = request
+        .headers
+        .get_joined("transfer-encoding")
+        .is_some_and(|codings| {
+            codings
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        });
+    if chunked {
+        return Body::Chunked(ChunkedReader::new(reader));
+    }
+
+    let content_length = request
+        .headers
         .get("content-length")
-        .unwrap_or(&String::from("0"))
-        .parse::<usize>()
+        .unwrap_or("0")
+        .parse::<u64>()
         .unwrap_or(0);
+    Body::Fixed(reader.take(content_length))
+}
 
-    let body = if content_length == 0 {
-        Vec::new()
-    } else {
-        let mut body = vec![0; content_length];
-        buf_reader
-            .read_exact(&mut body)
-            .map_err(RequestError::IoErr)?;
-        body
-    };
+pub(crate) enum Body<R> {
+    Fixed(Take<R>),
+    Chunked(ChunkedReader<R>),
+}
 
-    let persistent = version.contains("1.1") && !connexion_close;
-    Ok(Some(Request::new(
-        method, path, version, headers, body, persistent,
-    )))
+impl<R: BufRead> Read for Body<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Body::Fixed(reader) => reader.read(buf),
+            Body::Chunked(reader) => reader.read(buf),
+        }
+    }
+}
+
+// the default --max-line-length, the body reader has no config of its own
+const MAX_CHUNK_LINE: usize = 8192;
+
+// decodes `Transfer-Encoding: chunked` on the fly
+pub(crate) struct ChunkedReader<R> {
+    inner: R,
+    // bytes left in the current chunk
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    // size and trailer lines are capped like header lines, a client sending one
+    // without end must not make us buffer it
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        if !read_line_capped(&mut self.inner, &mut line, MAX_CHUNK_LINE)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk line too long",
+            ));
+        }
+        Ok(line)
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let line = self.read_line()?;
+        if line.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        // chunk extensions (`;name=value`) are ignored
+        let line = String::from_utf8_lossy(&line);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        // from_str_radix alone would take a sign too
+        Some(size)
+            .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))
+    }
+
+    fn skip_trailers(&mut self) -> io::Result<()> {
+        loop {
+            let line = self.read_line()?;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            let size = self.read_chunk_size()?;
+            if size == 0 {
+                self.skip_trailers()?;
+                self.done = true;
+                return Ok(0);
+            }
+            self.remaining = size;
+        }
+
+        let max = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.remaining -= n;
+        if self.remaining == 0 {
+            // CRLF closing the chunk data
+            let mut crlf = [0; 2];
+            self.inner.read_exact(&mut crlf)?;
+            if crlf != *b"\r\n" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk data not followed by CRLF",
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+// builds requests for handler tests without going through a socket and the parser
+#[cfg(test)]
+pub(crate) struct RequestBuilder {
+    method: String,
+    path: String,
+    version: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+#[cfg(test)]
+impl RequestBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            method: "GET".into(),
+            path: "/".into(),
+            version: "HTTP/1.1".into(),
+            headers: Headers::default(),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn method(mut self, method: &str) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    pub(crate) fn path(mut self, path: &str) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub(crate) fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub(crate) fn build(self) -> Request {
+        let persistent = self.version.contains("1.1");
+        Request::new(
+            &self.method,
+            &self.path,
+            &self.version,
+            self.headers,
+            self.body,
+            persistent,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_simple_request() {
+        let request = parse_request_from_bytes(
+            b"POST /echo?n=2 HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/echo");
+        assert_eq!(request.query, "n=2");
+        assert_eq!(request.headers.get("host"), Some("x"));
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[test]
+    fn regression_inputs_do_not_panic() {
+        let inputs: &[&[u8]] = &[
+            b"",
+            b"\r\n",
+            b"GET",
+            b"GET /",
+            b"GET / HTTP/1.1",
+            b"GET / HTTP/1.1\r\nmissing-colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nKey:\r\n\r\n",
+            b"GET / HTTP/1.1\r\n: value\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\nabc",
+            b"GET / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\na",
+            b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX: \xff\r\n\r\n",
+            b"GET /%zz%4 HTTP/1.1\r\n\r\n",
+        ];
+        for input in inputs {
+            let _ = parse_request_from_bytes(input);
+        }
+    }
+
+    #[test]
+    fn huge_content_length_reads_what_is_there() {
+        let request = parse_request_from_bytes(
+            b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\nabc",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[test]
+    fn missing_colon_is_an_invalid_header() {
+        let error =
+            parse_request_from_bytes(b"GET / HTTP/1.1\r\nmissing-colon\r\n\r\n").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RequestError>(),
+            Some(RequestError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn header_values_split_on_the_first_colon() {
+        let request = parse_request_from_bytes(
+            b"GET / HTTP/1.1\r\nHost:example.com\r\nX-Note: \t a: b: c \t\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request.headers.get("x-note"), Some("a: b: c"));
+
+        // whitespace before the colon is not allowed (RFC 9112 5.1)
+        let error =
+            parse_request_from_bytes(b"GET / HTTP/1.1\r\nHost : example.com\r\n\r\n").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RequestError>(),
+            Some(RequestError::InvalidHeader)
+        ));
+    }
+
+    // xorshift, enough to shuffle fragments around without pulling in a crate. The seed
+    // is fixed, so every run replays the same inputs: a smoke test, not a property test
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a [u8]]) -> &'a [u8] {
+            items[(self.next() % items.len() as u64) as usize]
+        }
+    }
+
+    #[test]
+    fn seeded_header_blocks_never_panic() {
+        let fragments: &[&[u8]] = &[
+            b"GET",
+            b" ",
+            b"  ",
+            b"/",
+            b"/files/",
+            b"?a=1&b",
+            b"HTTP/1.1",
+            b"HTTP/1.0",
+            b"\r\n",
+            b"\n",
+            b": ",
+            b":",
+            b"Content-Length",
+            b"Transfer-Encoding",
+            b"chunked",
+            b"gzip",
+            b"Connection",
+            b"close",
+            b"0",
+            b"5",
+            b"ffffffff",
+            b"99999999999999999999",
+            b"\xff\xfe",
+            b"\0",
+            b"\t",
+            b"abc",
+        ];
+
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..5000 {
+            let len = rng.next() % 40;
+            let mut input = Vec::new();
+            if rng.next() % 2 == 0 {
+                input.extend_from_slice(b"GET / HTTP/1.1\r\n");
+            }
+            for _ in 0..len {
+                input.extend_from_slice(rng.pick(fragments));
+            }
+            let _ = parse_request_from_bytes(&input);
+        }
+    }
+
+    #[test]
+    fn doubled_spaces_depend_on_strictness() {
+        let raw: &[u8] = b"GET  /echo/abc  HTTP/1.1\r\n\r\n";
+
+        let request = parse_request(&mut &raw[..], &Config::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path, "/echo/abc");
+        assert_eq!(request.version, "HTTP/1.1");
+
+        let strict = Config {
+            strict: true,
+            ..Default::default()
+        };
+        let error = parse_request(&mut &raw[..], &strict).unwrap_err();
+        let error = error.downcast_ref::<RequestError>().unwrap();
+        assert!(matches!(error, RequestError::InvalidStartLine));
+        assert!(matches!(error.status(), Some(HttpCode::BadRequest)));
+
+        let tab: &[u8] = b"GET\t/ HTTP/1.1\r\n\r\n";
+        assert!(parse_request(&mut &tab[..], &strict).is_err());
+        assert!(parse_request(&mut &b"GET / HTTP/1.1\r\n\r\n"[..], &strict).is_ok());
+    }
+
+    #[test]
+    fn control_characters_in_headers_are_rejected() {
+        for raw in [
+            &b"GET / HTTP/1.1\r\nX-Evil: a\rSet-Cookie: x\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nX-Evil: a\x00b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX Evil: a\r\n\r\n",
+        ] {
+            let error = parse_request_from_bytes(raw).unwrap_err();
+            let error = error.downcast_ref::<RequestError>().unwrap();
+            assert!(
+                matches!(error.status(), Some(HttpCode::BadRequest)),
+                "{raw:?}"
+            );
+        }
+
+        let request = parse_request_from_bytes(b"GET / HTTP/1.1\r\nX-Ok: a\tb\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.headers.get("x-ok"), Some("a\tb"));
+    }
+
+    fn chunked(raw: &[u8]) -> (io::Result<Vec<u8>>, Vec<u8>) {
+        let mut reader = raw;
+        let mut decoded = Vec::new();
+        let result = ChunkedReader::new(&mut reader)
+            .read_to_end(&mut decoded)
+            .map(|_| decoded);
+        (result, reader.to_vec())
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let (decoded, rest) = chunked(b"5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\nGET / HTTP/1.1\r\n");
+        assert_eq!(decoded.unwrap(), b"hello, world");
+        // the next pipelined request is left alone
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        // extensions are ignored, trailers are skipped
+        let (decoded, rest) =
+            chunked(b"5;name=value\r\nhello\r\nA; x\r\n0123456789\r\n0\r\nX-Checksum: 1\r\nX-Other: 2\r\n\r\nnext");
+        assert_eq!(decoded.unwrap(), b"hello0123456789");
+        assert_eq!(rest, b"next");
+    }
+
+    #[test]
+    fn chunk_lines_are_capped() {
+        let mut endless_extension = b"5;".to_vec();
+        endless_extension.resize(MAX_CHUNK_LINE + 1, b'x');
+        let mut endless_trailer = b"0\r\nX-Trailer: ".to_vec();
+        endless_trailer.resize(MAX_CHUNK_LINE * 2, b'x');
+        for raw in [endless_extension, endless_trailer] {
+            let (decoded, _) = chunked(&raw);
+            let error = decoded.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), "chunk line too long");
+        }
+    }
+
+    #[test]
+    fn bad_chunk_sizes_are_errors() {
+        for raw in [
+            &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+            b"-5\r\nhello\r\n",
+            b"+5\r\nhello\r\n0\r\n\r\n",
+            b"0x5\r\nhello\r\n0\r\n\r\n",
+            b"\r\n",
+            // the data runs past its size, or ends with a bare LF
+            b"5\r\nhelloXX\r\n0\r\n\r\n",
+            b"5\r\nhello\n0\r\n\r\n",
+        ] {
+            let (decoded, _) = chunked(raw);
+            let error = decoded.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{raw:?}");
+        }
+
+        // cut short: in the size line, and in the chunk data
+        for raw in [&b""[..], b"5\r\nhel"] {
+            let (decoded, _) = chunked(raw);
+            assert_eq!(decoded.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn header_count_is_capped() {
+        let head = |count: usize| {
+            let mut raw = String::from("GET / HTTP/1.1\r\n");
+            for i in 0..count {
+                raw.push_str(&format!("X-Header-{i}: {i}\r\n"));
+            }
+            raw.push_str("\r\n");
+            raw
+        };
+
+        let raw = head(50);
+        let request = parse_request(&mut raw.as_bytes(), &Config::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.headers.len(), 50);
+
+        let raw = head(200);
+        let error = parse_request(&mut raw.as_bytes(), &Config::default()).unwrap_err();
+        let error = error.downcast_ref::<RequestError>().unwrap();
+        assert!(matches!(error, RequestError::TooManyHeaders));
+        assert_eq!(error.status().map(|status| status.code()), Some(431));
+    }
+
+    #[test]
+    fn unknown_transfer_codings_are_501() {
+        let raw = b"POST /files/x HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        let error = parse_request(&mut &raw[..], &Config::default()).unwrap_err();
+        let error = error.downcast_ref::<RequestError>().unwrap();
+        assert!(
+            matches!(error, RequestError::UnsupportedTransferEncoding(coding) if coding == "gzip")
+        );
+        assert_eq!(error.status().map(|status| status.code()), Some(501));
+
+        let raw = b"POST /files/x HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n";
+        assert!(parse_request(&mut &raw[..], &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn transfer_codings_not_ending_in_chunked_are_400() {
+        for codings in ["identity", "chunked, identity", "chunked, chunked"] {
+            let raw = format!(
+                "POST /files/x HTTP/1.1\r\nTransfer-Encoding: {codings}\r\nContent-Length: 5\r\n\r\nhello"
+            );
+            let error = parse_request(&mut raw.as_bytes(), &Config::default()).unwrap_err();
+            let error = error.downcast_ref::<RequestError>().unwrap();
+            assert!(matches!(error, RequestError::ChunkedNotLast), "{codings}");
+            assert_eq!(error.status().map(|status| status.code()), Some(400));
+        }
+        let raw = b"POST /files/x HTTP/1.1\r\nTransfer-Encoding: identity\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(parse_request(&mut &raw[..], &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn non_utf8_request_lines_are_400() {
+        let raw = b"GET /caf\xe9 HTTP/1.1\r\n\r\n";
+        let error = parse_request(&mut &raw[..], &Config::default()).unwrap_err();
+        let error = error.downcast_ref::<RequestError>().unwrap();
+        assert!(matches!(error, RequestError::InvalidUtf8));
+        assert_eq!(error.status().map(|status| status.code()), Some(400));
+    }
+
+    #[test]
+    fn long_targets_are_414_and_malformed_ones_400() {
+        let config = Config {
+            max_uri_length: 32,
+            ..Default::default()
+        };
+        let status = |raw: &[u8]| {
+            let error = parse_request(&mut &raw[..], &config).unwrap_err();
+            let error = error.downcast_ref::<RequestError>().unwrap();
+            error.status().map(|status| status.code())
+        };
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        assert_eq!(status(long.as_bytes()), Some(414));
+        // malformed too, but the length is what the client has to fix first
+        let long = format!("GET {} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        assert_eq!(status(long.as_bytes()), Some(414));
+
+        assert_eq!(status(b"GET echo/abc HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET /a\x01b HTTP/1.1\r\n\r\n"), Some(400));
+        for target in ["/a%20b", "/a%09b", "/a%0d%0ab", "/a%7Fb"] {
+            let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+            assert_eq!(status(raw.as_bytes()), Some(400), "{target}");
+        }
+        // the query is not part of the path, spaces are fine there
+        assert!(parse_request(&mut &b"GET /a?q=b%20c HTTP/1.1\r\n\r\n"[..], &config).is_ok());
+        assert_eq!(status(b"GET * HTTP/1.1\r\n\r\n"), Some(400));
+        assert!(parse_request(&mut &b"OPTIONS * HTTP/1.1\r\n\r\n"[..], &config).is_ok());
+    }
+
+    #[test]
+    fn giant_header_lines_are_431_without_reading_them_whole() {
+        let mut raw = b"GET / HTTP/1.1\r\nX-Big: ".to_vec();
+        raw.resize(raw.len() + (1 << 20), b'a');
+        raw.extend_from_slice(b"\r\n\r\n");
+
+        let mut reader = &raw[..];
+        let error = parse_request(&mut reader, &Config::default()).unwrap_err();
+        let error = error.downcast_ref::<RequestError>().unwrap();
+        assert!(matches!(error, RequestError::HeaderLineTooLong));
+        assert_eq!(error.status().map(|status| status.code()), Some(431));
+        // the header line was never consumed
+        assert_eq!(reader.len(), raw.len() - b"GET / HTTP/1.1\r\n".len());
+
+        let config = Config {
+            max_line_length: 16,
+            ..Default::default()
+        };
+        let fits = b"GET / HTTP/1.1\r\nX-Ok: 12345678\r\n\r\n";
+        assert!(parse_request(&mut &fits[..], &config).unwrap().is_some());
+        let over = b"GET / HTTP/1.1\r\nX-Ok: 123456789\r\n\r\n";
+        assert!(parse_request(&mut &over[..], &config).is_err());
+    }
 }
@@ -1,34 +1,408 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
-use crate::request::Request;
+use crate::config::{Config, MAX_INJECTED_DELAY};
+use crate::embedded::{self, EmbeddedFile};
+use crate::metrics;
+use crate::middleware::Middlewares;
+use crate::mime;
+use crate::negotiation;
+use crate::request::{self, Request};
+use crate::stream::{StreamedBody, STREAM_THRESHOLD};
 use crate::{HttpCode, Response};
 
-pub(crate) fn handle_request(request: &Request, dest_dir: &str) -> Response {
+// uploads go from the socket straight to disk instead of through `request.body`
+pub(crate) fn streams_body(request: &Request) -> bool {
+    request.method == *"POST" && request.path.starts_with("/files/")
+}
+
+// everything some route answers to, for the server-wide `OPTIONS *`
+const SERVER_METHODS: [&str; 5] = ["GET", "HEAD", "POST", "OPTIONS", "TRACE"];
+
+enum Route<'a> {
+    Root,
+    UserAgent,
+    Headers,
+    Ip,
+    Metrics,
+    DiskStats,
+    EchoBody,
+    Echo(&'a str),
+    Delay(&'a str),
+    Status(&'a str),
+    Files(&'a str),
+}
+
+impl<'a> Route<'a> {
+    fn find(path: &'a str) -> Option<Self> {
+        let route = match path {
+            "/" => Route::Root,
+            "/user-agent" => Route::UserAgent,
+            "/headers" => Route::Headers,
+            "/ip" => Route::Ip,
+            "/metrics" => Route::Metrics,
+            "/stats/disk" => Route::DiskStats,
+            "/echo" => Route::EchoBody,
+            path if path.starts_with("/echo/") => Route::Echo(&path[6..]),
+            path if path.starts_with("/delay/") => Route::Delay(&path[7..]),
+            path if path.starts_with("/status/") => Route::Status(&path[8..]),
+            path if path.starts_with("/files/") => Route::Files(&path[7..]),
+            _ => return None,
+        };
+        Some(route)
+    }
+
+    fn methods(&self, config: &Config) -> &'static [&'static str] {
+        match self {
+            Route::Root
+            | Route::UserAgent
+            | Route::Headers
+            | Route::Ip
+            | Route::Metrics
+            | Route::DiskStats
+            | Route::Echo(_)
+            | Route::Delay(_)
+            | Route::Status(_) => &["GET", "HEAD"],
+            Route::EchoBody => &["POST"],
+            Route::Files(_) if !config.accepts_uploads() => &["GET", "HEAD"],
+            Route::Files(_) => &["GET", "HEAD", "POST"],
+        }
+    }
+
+    fn middlewares(&self) -> Middlewares {
+        match self {
+            // scraped often on a local network, not worth the cpu
+            Route::Metrics => Middlewares { compress: false },
+            _ => Middlewares::default(),
+        }
+    }
+}
+
+pub(crate) fn handle_request(
+    request: &Request,
+    body: &mut dyn Read,
+    config: &Config,
+) -> (Response, Middlewares) {
+    let middlewares =
+        Route::find(&request.path).map_or_else(Middlewares::default, |route| route.middlewares());
+    (dispatch(request, body, config), middlewares)
+}
+
+fn dispatch(request: &Request, body: &mut dyn Read, config: &Config) -> Response {
     // the router...
     let version = request.version.clone();
-    match request.path.as_str() {
-        "/" => Response::default(),
-        "/user-agent" => handle_user_agent(request),
-        path if path.starts_with("/echo/") => handle_echo(request, &path[6..]),
-        path if request.method == *"GET" && path.starts_with("/files/") => {
-            handle_file_content(request, &path[7..], dest_dir)
+    let dest_dir = config.directory.as_str();
+    if config.https_redirect {
+        return redirect_to_https(request, HttpCode::MovedPermanently);
+    }
+    if config.trust_proxy && wants_upgrade(request) {
+        let mut response = redirect_to_https(request, HttpCode::TemporaryRedirect);
+        response
+            .headers
+            .push(("Vary".into(), "Upgrade-Insecure-Requests".into()));
+        return response;
+    }
+
+    if request.method == *"TRACE" {
+        return handle_trace(request, config);
+    }
+
+    if request.method == *"OPTIONS" && matches!(request.path.as_str(), "*" | "/") {
+        return options_response(SERVER_METHODS.join(", "));
+    }
+
+    // no route implements it: 501, a 405 would suggest another resource might
+    if !SERVER_METHODS.contains(&request.method.as_str()) {
+        return Response {
+            status: HttpCode::NotImplemented,
+            headers: vec![("Allow".into(), SERVER_METHODS.join(", "))],
+            content: format!("Method {} is not implemented", request.method).into_bytes(),
+            ..Default::default()
+        };
+    }
+
+    let Some(route) = Route::find(&request.path) else {
+        return Response {
+            version,
+            status: HttpCode::NotFound,
+            ..Default::default()
+        };
+    };
+
+    let methods = route.methods(config);
+    if request.method == *"OPTIONS" {
+        return options_response([methods, &["OPTIONS"]].concat().join(", "));
+    }
+    if !methods.contains(&request.method.as_str()) {
+        return method_not_allowed(request, methods);
+    }
+
+    match route {
+        Route::Root => Response::default(),
+        Route::UserAgent => handle_user_agent(request),
+        Route::Headers => handle_headers(request, config),
+        Route::Ip => handle_ip(request),
+        Route::Metrics => Response {
+            content_type: Some("text/plain; version=0.0.4".into()),
+            content: metrics::render().into_bytes(),
+            ..Default::default()
+        },
+        Route::DiskStats => handle_disk_stats(config),
+        Route::EchoBody => handle_echo_body(request),
+        Route::Echo(text) => handle_echo(request, text),
+        Route::Delay(seconds) => handle_delay(seconds),
+        Route::Status(code) => handle_status(code),
+        Route::Files(filename) if request.method == *"POST" => {
+            handle_file_upload(request, body, filename, dest_dir, config)
         }
-        path if request.method == *"POST" && path.starts_with("/files/") => {
-            handle_file_upload(request, &path[7..], dest_dir)
+        Route::Files(filename) if config.embedded => {
+            handle_embedded_content(request, filename, embedded::FILES, config)
         }
-        _ => Response {
-            version,
+        Route::Files(filename) => handle_file_content(request, filename, dest_dir, config),
+    }
+}
+
+// a client asking for https (`Upgrade-Insecure-Requests: 1`) whose request the
+// proxy in front received over plain http
+fn wants_upgrade(request: &Request) -> bool {
+    let forwarded_proto = request
+        .headers
+        .get("x-forwarded-proto")
+        // the first proxy, the one the client talked to, comes first
+        .and_then(|protos| protos.split(',').next())
+        .map(str::trim);
+    request.headers.get("upgrade-insecure-requests") == Some("1")
+        && forwarded_proto.is_some_and(|proto| proto.eq_ignore_ascii_case("http"))
+}
+
+// everything on the plain http listener moves to the same URL over https
+fn redirect_to_https(request: &Request, status: HttpCode) -> Response {
+    let Some(host) = request.headers.get("host").filter(|host| !host.is_empty()) else {
+        return Response {
+            status: HttpCode::BadRequest,
+            content: String::from("Missing Host header").into_bytes(),
+            ..Default::default()
+        };
+    };
+
+    // the plain http port means nothing for https, keep the name only
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    Response {
+        status,
+        headers: vec![(
+            "Location".into(),
+            format!("https://{host}{}", request.target()),
+        )],
+        ..Default::default()
+    }
+}
+
+fn method_not_allowed(request: &Request, methods: &[&str]) -> Response {
+    let accept = request.headers.get_joined("accept").unwrap_or_default();
+    let (content_type, content) = match negotiation::best_match(
+        &accept,
+        &["text/plain", "application/json"],
+    ) {
+        Some("application/json") => {
+            let allowed = methods
+                .iter()
+                .map(|method| json_string(method))
+                .collect::<Vec<String>>()
+                .join(",");
+            let body = format!(
+                r#"{{"error":"method not allowed","method":{},"path":{},"allowed":[{allowed}]}}"#,
+                json_string(&request.method),
+                json_string(&request.path)
+            );
+            ("application/json", body)
+        }
+        _ => (
+            "text/plain",
+            format!("Method {} not allowed on {}", request.method, request.path),
+        ),
+    };
+
+    Response {
+        status: HttpCode::MethodNotAllowed,
+        content_type: Some(content_type.to_string()),
+        headers: vec![("Allow".into(), methods.join(", "))],
+        content: content.into_bytes(),
+        ..Default::default()
+    }
+}
+
+// a quoted JSON string literal
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn handle_trace(request: &Request, config: &Config) -> Response {
+    // Max-Forwards only matters to proxies: being the origin, this server is always
+    // the final recipient, so `Max-Forwards: 0` or not, the request is echoed right away
+    let mut message = format!(
+        "{} {} {}\r\n",
+        request.method,
+        request.target(),
+        request.version
+    );
+    // echoing credentials back is what cross-site tracing steals them with
+    for (name, value) in redacted_headers(request, config) {
+        message.push_str(&format!("{name}: {value}\r\n"));
+    }
+    message.push_str("\r\n");
+
+    Response {
+        content_type: Some("message/http".into()),
+        content: message.into_bytes(),
+        ..Default::default()
+    }
+}
+
+const MAX_ECHO_REPEAT: usize = 10_000;
+
+fn handle_echo(request: &Request, repeat: &str) -> Response {
+    let count = request
+        .query_param("n")
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1)
+        .min(MAX_ECHO_REPEAT);
+
+    serve_ranged(
+        repeat.repeat(count).into_bytes(),
+        request.headers.get("range"),
+        "text/plain".into(),
+        Vec::new(),
+    )
+}
+
+// for clients testing their timeouts, unlike --inject-delay it needs no setup
+fn handle_delay(seconds: &str) -> Response {
+    let Some(seconds) = seconds
+        .parse::<u64>()
+        .ok()
+        .filter(|seconds| *seconds <= MAX_INJECTED_DELAY.as_secs())
+    else {
+        return Response {
+            status: HttpCode::BadRequest,
+            content: format!(
+                "Delay must be a number of seconds up to {}",
+                MAX_INJECTED_DELAY.as_secs()
+            )
+            .into_bytes(),
+            ..Default::default()
+        };
+    };
+
+    std::thread::sleep(std::time::Duration::from_secs(seconds));
+    Response {
+        content_type: Some("application/json".into()),
+        content: format!(r#"{{"delay":{seconds}}}"#).into_bytes(),
+        ..Default::default()
+    }
+}
+
+// deeper than that is more likely a mistake than a real tree
+const MAX_WALK_DEPTH: usize = 32;
+
+#[derive(Debug, Default, PartialEq)]
+struct DiskUsage {
+    files: u64,
+    bytes: u64,
+}
+
+// symlinks are not followed, a link back up the tree cannot make the walk loop
+fn disk_usage(dir: &Path, depth: usize, usage: &mut DiskUsage) -> io::Result<()> {
+    if depth > MAX_WALK_DEPTH {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            disk_usage(&entry.path(), depth + 1, usage)?;
+        } else if file_type.is_file() {
+            usage.files += 1;
+            usage.bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(())
+}
+
+fn handle_disk_stats(config: &Config) -> Response {
+    if config.embedded {
+        return Response {
             status: HttpCode::NotFound,
+            content: String::from("No directory is served").into_bytes(),
+            ..Default::default()
+        };
+    }
+
+    let mut usage = DiskUsage::default();
+    match disk_usage(Path::new(&config.directory), 0, &mut usage) {
+        Ok(()) => Response {
+            content_type: Some("application/json".into()),
+            content: format!(r#"{{"files":{},"bytes":{}}}"#, usage.files, usage.bytes).into_bytes(),
+            ..Default::default()
+        },
+        Err(e) => Response {
+            status: HttpCode::InternalServerError,
+            content: format!("Error reading {}: {e}", config.directory).into_bytes(),
+            ..Default::default()
+        },
+    }
+}
+
+// a 1xx is never final, a client would keep waiting for the real response
+fn handle_status(code: &str) -> Response {
+    match code.parse::<u16>() {
+        Ok(code @ 200..=599) => Response {
+            status: HttpCode::from_code(code),
+            ..Default::default()
+        },
+        // a 1xx is interim, the client would go on waiting for the final response
+        Ok(100..=199) => Response {
+            status: HttpCode::BadRequest,
+            content: String::from("Informational (1xx) codes cannot be sent as the response")
+                .into_bytes(),
+            ..Default::default()
+        },
+        _ => Response {
+            status: HttpCode::BadRequest,
+            content: String::from("Status must be a code from 100 to 599").into_bytes(),
             ..Default::default()
         },
     }
 }
 
-fn handle_echo(_r: &Request, repeat: &str) -> Response {
+fn handle_echo_body(request: &Request) -> Response {
     Response {
-        content: repeat.to_string().into_bytes(),
+        content_type: Some(
+            request
+                .headers
+                .get("content-type")
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+        ),
+        content: request.body.clone(),
         ..Default::default()
     }
 }
@@ -48,42 +422,482 @@ fn handle_user_agent(request: &Request) -> Response {
     }
 }
 
-fn handle_file_content(_r: &Request, filename: &str, dest_dir: &str) -> Response {
-    let mut path = PathBuf::new();
-    path.push(dest_dir);
-    path.push(filename);
+// credentials end up in shared debugging output otherwise
+const SENSITIVE_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+fn redacted_headers<'a>(
+    request: &'a Request,
+    config: &Config,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    let show_sensitive = config.show_sensitive_headers;
+    request.headers.iter().map(move |(name, value)| {
+        let sensitive = SENSITIVE_HEADERS
+            .iter()
+            .any(|sensitive| name.eq_ignore_ascii_case(sensitive));
+        if sensitive && !show_sensitive {
+            (name, "[redacted]")
+        } else {
+            (name, value)
+        }
+    })
+}
+
+fn handle_headers(request: &Request, config: &Config) -> Response {
+    let headers = redacted_headers(request, config);
+
+    let accept = request.headers.get_joined("accept").unwrap_or_default();
+    let (content_type, content) =
+        match negotiation::best_match(&accept, &["text/plain", "application/json"]) {
+            Some("application/json") => {
+                // an array keeps the order and repeated names
+                let fields = headers
+                    .map(|(name, value)| {
+                        format!(
+                            r#"{{"name":{},"value":{}}}"#,
+                            json_string(name),
+                            json_string(value)
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(",");
+                ("application/json", format!("[{fields}]"))
+            }
+            _ => (
+                "text/plain",
+                headers
+                    .map(|(name, value)| format!("{name}: {value}\n"))
+                    .collect::<String>(),
+            ),
+        };
 
-    if let Ok(content) = std::fs::read_to_string(path) {
+    Response {
+        content_type: Some(content_type.to_string()),
+        content: content.into_bytes(),
+        ..Default::default()
+    }
+}
+
+fn handle_ip(request: &Request) -> Response {
+    if let Some(peer) = request.peer {
         Response {
-            content_type: String::from("application/octet-stream"),
-            content: content.into_bytes(),
+            content: peer.ip().to_string().into_bytes(),
             ..Default::default()
         }
     } else {
+        // unix domain socket clients have no address
         Response {
             status: HttpCode::NotFound,
-            content: String::from("File not found").into_bytes(),
+            content: String::from("No peer address for this connection").into_bytes(),
             ..Default::default()
         }
     }
 }
 
-fn handle_file_upload(request: &Request, filename: &str, dest_dir: &str) -> Response {
+// a 200 with an empty body rather than a 204, so it goes out with
+// `Content-Length: 0` and clients relying on it know the message is over
+fn options_response(allow: String) -> Response {
+    Response {
+        headers: vec![("Allow".into(), allow)],
+        ..Default::default()
+    }
+}
+
+// file names arrive percent-encoded in the request target, and must stay inside
+// the served directory: no absolute path, no `..`, and no NUL (`%00`) the OS
+// would take as the end of the name
+fn decode_filename(filename: &str) -> Option<String> {
+    let filename = String::from_utf8(request::percent_decode(filename)).ok()?;
+    let escapes = filename.starts_with('/')
+        || filename.contains('\0')
+        || filename.split('/').any(|segment| segment == "..");
+    if escapes {
+        None
+    } else {
+        Some(filename)
+    }
+}
+
+fn invalid_filename() -> Response {
+    Response {
+        status: HttpCode::BadRequest,
+        content: String::from("Invalid file name").into_bytes(),
+        ..Default::default()
+    }
+}
+
+const INDEX_FILE: &str = "index.html";
+
+fn handle_file_content(
+    request: &Request,
+    filename: &str,
+    dest_dir: &str,
+    config: &Config,
+) -> Response {
+    let Some(filename) = decode_filename(filename) else {
+        return invalid_filename();
+    };
     let mut path = PathBuf::new();
     path.push(dest_dir);
-    path.push(filename);
+    path.push(&filename);
+
+    // directories are only addressed with a trailing slash
+    if path.is_dir() && !filename.is_empty() && !filename.ends_with('/') {
+        let target = format!("{}/", request.path);
+        return Response {
+            status: HttpCode::MovedPermanently,
+            headers: vec![(
+                "Location".into(),
+                absolute_url(request, &config.scheme, &target),
+            )],
+            ..Default::default()
+        };
+    }
+
+    // a directory is represented by its index page
+    let mut filename = filename;
+    let mut content_location = None;
+    if path.is_dir() {
+        path.push(INDEX_FILE);
+        filename.push_str(INDEX_FILE);
+        content_location = Some(format!("{}{INDEX_FILE}", request.path));
+    }
+
+    // `If-None-Match: *` matches any current representation
+    if if_none_match_any(request) && path.is_file() {
+        return Response {
+            status: HttpCode::NotModified,
+            ..Default::default()
+        };
+    }
+
+    let content_type = mime::content_type(&path, &config.charset, config.sniff);
+    let mut headers = download_headers(request, &filename, config);
+    if let Some(location) = content_location {
+        headers.push(("Content-Location".into(), location));
+    }
+
+    // big files stream, a range of one is seeked to rather than read whole
+    if let Some(body) = open_large_file(&path) {
+        return serve_streamed(body, request.headers.get("range"), content_type, headers);
+    }
+
+    let Ok(content) = std::fs::read(path) else {
+        return Response {
+            status: HttpCode::NotFound,
+            content: String::from("File not found").into_bytes(),
+            ..Default::default()
+        };
+    };
+
+    serve_ranged(content, request.headers.get("range"), content_type, headers)
+}
+
+fn open_large_file(path: &Path) -> Option<StreamedBody> {
+    let file = std::fs::File::open(path).ok()?;
+    let len = file
+        .metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())?
+        .len();
+    (len >= STREAM_THRESHOLD).then(|| StreamedBody::new(file, len))
+}
+
+// the `handle_file_content` of files compiled into the binary
+fn handle_embedded_content(
+    request: &Request,
+    filename: &str,
+    files: &[EmbeddedFile],
+    config: &Config,
+) -> Response {
+    let Some(mut filename) = decode_filename(filename) else {
+        return invalid_filename();
+    };
+    let mut content_location = None;
+    if filename.is_empty() || filename.ends_with('/') {
+        filename.push_str(INDEX_FILE);
+        content_location = Some(format!("{}{INDEX_FILE}", request.path));
+    }
+    let Some(file) = embedded::find(files, &filename) else {
+        return Response {
+            status: HttpCode::NotFound,
+            content: String::from("File not found").into_bytes(),
+            ..Default::default()
+        };
+    };
+
+    let etag = ("ETag".to_string(), file.etag.to_string());
+    let cached = request.headers.get("if-none-match").is_some_and(|etags| {
+        etags
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == file.etag)
+    });
+    if cached {
+        return Response {
+            status: HttpCode::NotModified,
+            headers: vec![etag],
+            ..Default::default()
+        };
+    }
+
+    // not on disk, nothing to sniff
+    let content_type = mime::content_type(Path::new(&filename), &config.charset, false);
+    let mut headers = download_headers(request, &filename, config);
+    headers.push(etag);
+    if let Some(location) = content_location {
+        headers.push(("Content-Location".into(), location));
+    }
+    serve_ranged(
+        file.content.to_vec(),
+        request.headers.get("range"),
+        content_type,
+        headers,
+    )
+}
+
+fn download_headers(request: &Request, filename: &str, config: &Config) -> Vec<(String, String)> {
+    if config.force_download || request.query_param("download").is_some_and(|d| d == "1") {
+        vec![("Content-Disposition".into(), content_disposition(filename))]
+    } else {
+        Vec::new()
+    }
+}
+
+// `content` as a whole or the slice a `Range` header asks for
+fn serve_ranged(
+    content: Vec<u8>,
+    range: Option<&str>,
+    content_type: String,
+    mut headers: Vec<(String, String)>,
+) -> Response {
+    headers.push(("Accept-Ranges".into(), "bytes".into()));
 
-    match File::create(path) {
-        Ok(mut file) => match file.write_all(&request.body) {
-            Ok(()) => Response {
-                status: HttpCode::Created,
+    let total = content.len() as u64;
+    match range.and_then(|range| parse_range(range, total)) {
+        Some(ByteRange::Satisfiable(start, end)) => {
+            headers.push(content_range(start, end, total));
+            Response {
+                status: HttpCode::PartialContent,
+                content_type: Some(content_type),
+                headers,
+                partial: true,
+                content: content[start as usize..=end as usize].to_vec(),
                 ..Default::default()
-            },
-            Err(_) => Response {
-                status: HttpCode::InternalServerError,
-                content: String::from("Failed to write file").into_bytes(),
+            }
+        }
+        Some(ByteRange::Unsatisfiable) => range_not_satisfiable(total),
+        None => Response {
+            content_type: Some(content_type),
+            headers,
+            content,
+            ..Default::default()
+        },
+    }
+}
+
+// the `serve_ranged` of a file too big to load
+fn serve_streamed(
+    body: StreamedBody,
+    range: Option<&str>,
+    content_type: String,
+    mut headers: Vec<(String, String)>,
+) -> Response {
+    headers.push(("Accept-Ranges".into(), "bytes".into()));
+
+    let total = body.len();
+    let body = match range.and_then(|range| parse_range(range, total)) {
+        Some(ByteRange::Satisfiable(start, end)) => {
+            headers.push(content_range(start, end, total));
+            return Response {
+                status: HttpCode::PartialContent,
+                content_type: Some(content_type),
+                headers,
+                partial: true,
+                stream: Some(body.slice(start, end - start + 1)),
                 ..Default::default()
-            },
+            };
+        }
+        Some(ByteRange::Unsatisfiable) => return range_not_satisfiable(total),
+        None => body,
+    };
+    Response {
+        content_type: Some(content_type),
+        headers,
+        stream: Some(body),
+        ..Default::default()
+    }
+}
+
+fn content_range(start: u64, end: u64, total: u64) -> (String, String) {
+    (
+        "Content-Range".into(),
+        format!("bytes {start}-{end}/{total}"),
+    )
+}
+
+fn range_not_satisfiable(total: u64) -> Response {
+    Response {
+        status: HttpCode::RangeNotSatisfiable,
+        headers: vec![("Content-Range".into(), format!("bytes */{total}"))],
+        ..Default::default()
+    }
+}
+
+fn if_none_match_any(request: &Request) -> bool {
+    request
+        .headers
+        .get("if-none-match")
+        .is_some_and(|etags| etags.trim() == "*")
+}
+
+// `scheme://host/path` from the Host header, or just the path (a relative reference)
+// when the client did not send one
+fn absolute_url(request: &Request, scheme: &str, path: &str) -> String {
+    match request.headers.get("host") {
+        Some(host) if !host.is_empty() => format!("{scheme}://{host}{path}"),
+        _ => path.to_string(),
+    }
+}
+
+// plain `filename` for old clients, RFC 5987 `filename*` when it is not ascii
+fn content_disposition(filename: &str) -> String {
+    let basename = filename.rsplit('/').next().unwrap_or(filename);
+    let fallback = basename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    if fallback == basename {
+        return format!("attachment; filename=\"{fallback}\"");
+    }
+
+    // attr-char from RFC 5987, everything else is percent-encoded
+    let encoded = basename
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[derive(Debug)]
+enum ByteRange {
+    // inclusive bounds, already clamped to the content
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+// only a single range is supported, anything else is ignored (full response)
+fn parse_range(header: &str, total: u64) -> Option<ByteRange> {
+    // range units are case-insensitive, ours is the only one
+    let (unit, spec) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = match (start.is_empty(), end.is_empty()) {
+        // bytes=-500 => the last 500 bytes
+        (true, false) => {
+            let suffix = end.parse::<u64>().ok()?;
+            if suffix == 0 || total == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(total.saturating_sub(suffix), total - 1)
+            }
+        }
+        // bytes=500- => from 500 to the end
+        (false, true) => {
+            let start = start.parse::<u64>().ok()?;
+            if start >= total {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(start, total - 1)
+            }
+        }
+        (false, false) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = end.parse::<u64>().ok()?;
+            if start > end {
+                return None;
+            }
+            if start >= total {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(start, end.min(total - 1))
+            }
+        }
+        (true, true) => return None,
+    };
+
+    Some(range)
+}
+
+fn handle_file_upload(
+    request: &Request,
+    body: &mut dyn Read,
+    filename: &str,
+    dest_dir: &str,
+    config: &Config,
+) -> Response {
+    let Some(filename) = decode_filename(filename) else {
+        return invalid_filename();
+    };
+    let mut path = PathBuf::new();
+    path.push(dest_dir);
+    path.push(filename);
+
+    // `If-None-Match: *` => only create, never overwrite (checked atomically)
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if if_none_match_any(request) {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+
+    match options.open(path) {
+        Ok(mut file) => {
+            match set_upload_mode(&file, config).and_then(|()| io::copy(body, &mut file)) {
+                Ok(_) => Response {
+                    status: HttpCode::Created,
+                    ..Default::default()
+                },
+                Err(_) => Response {
+                    status: HttpCode::InternalServerError,
+                    content: String::from("Failed to write file").into_bytes(),
+                    ..Default::default()
+                },
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Response {
+            status: HttpCode::PreconditionFailed,
+            content: String::from("File already exists").into_bytes(),
+            ..Default::default()
         },
         Err(_) => Response {
             status: HttpCode::InternalServerError,
@@ -92,3 +906,733 @@ fn handle_file_upload(request: &Request, filename: &str, dest_dir: &str) -> Resp
         },
     }
 }
+
+// explicitly, the mode given when creating a file would still go through the umask
+#[cfg(unix)]
+fn set_upload_mode(file: &std::fs::File, config: &Config) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match config.upload_mode {
+        Some(mode) => file.set_permissions(std::fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_upload_mode(_file: &std::fs::File, _config: &Config) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+    use crate::request::RequestBuilder;
+
+    fn handle(request: &Request) -> Response {
+        handle_request(request, &mut io::empty(), &Config::default()).0
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn root_is_ok() {
+        let response = handle(&RequestBuilder::new().build());
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert!(response.content.is_empty());
+    }
+
+    #[test]
+    fn echo_returns_the_path_segment() {
+        let response = handle(&RequestBuilder::new().path("/echo/abc").build());
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content_type(), Some("text/plain"));
+        assert_eq!(response.content, b"abc");
+    }
+
+    #[test]
+    fn echo_repeats_and_clamps() {
+        let response = handle(&RequestBuilder::new().path("/echo/ab?n=3").build());
+        assert_eq!(response.content, b"ababab");
+
+        let response = handle(&RequestBuilder::new().path("/echo/a?n=99999999").build());
+        assert_eq!(response.content.len(), MAX_ECHO_REPEAT);
+    }
+
+    #[test]
+    fn echo_body_round_trips_content_type() {
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/echo")
+            .header("Content-Type", "application/json")
+            .body(r#"{"a":1}"#)
+            .build();
+        let response = handle(&request);
+        assert_eq!(response.content_type.as_deref(), Some("application/json"));
+        assert_eq!(response.content, br#"{"a":1}"#);
+    }
+
+    #[test]
+    fn user_agent_is_reflected() {
+        let request = RequestBuilder::new()
+            .path("/user-agent")
+            .header("User-Agent", "foobar/1.2.3")
+            .build();
+        let response = handle(&request);
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content, b"foobar/1.2.3");
+    }
+
+    #[test]
+    fn missing_user_agent_is_a_bad_request() {
+        let response = handle(&RequestBuilder::new().path("/user-agent").build());
+        assert!(matches!(response.status, HttpCode::BadRequest));
+    }
+
+    #[test]
+    fn unknown_path_is_not_found() {
+        let response = handle(&RequestBuilder::new().path("/nope").build());
+        assert!(matches!(response.status, HttpCode::NotFound));
+    }
+
+    #[test]
+    fn wrong_method_is_not_allowed() {
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/user-agent")
+            .build();
+        let response = handle(&request);
+        assert!(matches!(response.status, HttpCode::MethodNotAllowed));
+        assert_eq!(header(&response, "allow"), Some("GET, HEAD"));
+        assert_eq!(response.content, b"Method POST not allowed on /user-agent");
+    }
+
+    #[test]
+    fn not_allowed_body_follows_accept() {
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/echo/x")
+            .header("Accept", "application/json")
+            .build();
+        let response = handle(&request);
+        assert_eq!(response.content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            r#"{"error":"method not allowed","method":"POST","path":"/echo/x","allowed":["GET","HEAD"]}"#
+        );
+    }
+
+    #[test]
+    fn ip_reads_the_peer_address() {
+        let mut request = RequestBuilder::new().path("/ip").build();
+        request.peer = Some("192.0.2.7:51000".parse().unwrap());
+        let (response, _) = handle_request(&request, &mut io::empty(), &Config::default());
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content, b"192.0.2.7");
+
+        // unix domain socket clients
+        request.peer = None;
+        let (response, _) = handle_request(&request, &mut io::empty(), &Config::default());
+        assert!(matches!(response.status, HttpCode::NotFound));
+        assert_eq!(response.content, b"No peer address for this connection");
+    }
+
+    #[test]
+    fn headers_are_reflected_and_credentials_redacted() {
+        let request = RequestBuilder::new()
+            .path("/headers")
+            .header("X-Trace", "abc")
+            .header("authorization", "Bearer secret")
+            .header("Cookie", "session=1")
+            .build();
+        let response = handle(&request);
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            "X-Trace: abc\nauthorization: [redacted]\nCookie: [redacted]\n"
+        );
+
+        let config = Config {
+            show_sensitive_headers: true,
+            ..Default::default()
+        };
+        let request = RequestBuilder::new()
+            .path("/headers")
+            .header("Authorization", "Bearer secret")
+            .header("Accept", "application/json")
+            .build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert_eq!(response.content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            r#"[{"name":"Authorization","value":"Bearer secret"},{"name":"Accept","value":"application/json"}]"#
+        );
+    }
+
+    #[test]
+    fn trace_echoes_the_request_with_credentials_redacted() {
+        let request = RequestBuilder::new()
+            .method("TRACE")
+            .path("/echo/x")
+            .header("Max-Forwards", "0")
+            .header("Authorization", "Bearer secret")
+            .header("Cookie", "session=1")
+            .header("X-Trace", "abc")
+            .build();
+        // the origin server is the final recipient, Max-Forwards: 0 or not
+        let (response, _) = handle_request(&request, &mut io::empty(), &Config::default());
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content_type.as_deref(), Some("message/http"));
+        assert_eq!(
+            String::from_utf8(response.content).unwrap(),
+            "TRACE /echo/x HTTP/1.1\r\n\
+             Max-Forwards: 0\r\n\
+             Authorization: [redacted]\r\n\
+             Cookie: [redacted]\r\n\
+             X-Trace: abc\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn options_asterisk_lists_server_methods() {
+        for path in ["*", "/"] {
+            let request = RequestBuilder::new().method("OPTIONS").path(path).build();
+            let response = handle(&request);
+            assert!(matches!(response.status, HttpCode::Ok));
+            assert_eq!(
+                header(&response, "allow"),
+                Some("GET, HEAD, POST, OPTIONS, TRACE")
+            );
+            assert!(response.content.is_empty());
+        }
+    }
+
+    #[test]
+    fn echo_serves_ranges() {
+        let request = RequestBuilder::new()
+            .path("/echo/abcdef")
+            .header("Range", "bytes=1-3")
+            .build();
+        let response = handle(&request);
+        assert!(matches!(response.status, HttpCode::PartialContent));
+        assert_eq!(header(&response, "content-range"), Some("bytes 1-3/6"));
+        assert_eq!(response.content, b"bcd");
+
+        let request = RequestBuilder::new()
+            .path("/echo/abc")
+            .header("Range", "bytes=10-")
+            .build();
+        assert!(matches!(
+            handle(&request).status,
+            HttpCode::RangeNotSatisfiable
+        ));
+    }
+
+    #[test]
+    fn embedded_files_are_served_from_memory() {
+        static FILES: &[EmbeddedFile] = &[EmbeddedFile {
+            name: "css/site.css",
+            content: b"body{}",
+            etag: "\"0123456789abcdef\"",
+        }];
+        let config = Config {
+            directory: "/nonexistent".into(),
+            embedded: true,
+            ..Default::default()
+        };
+
+        let request = RequestBuilder::new().path("/files/css/site.css").build();
+        let response = handle_embedded_content(&request, "css/site.css", FILES, &config);
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(
+            response.content_type.as_deref(),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(header(&response, "etag"), Some("\"0123456789abcdef\""));
+        assert_eq!(response.content, b"body{}");
+
+        let request = RequestBuilder::new()
+            .path("/files/css/site.css")
+            .header("If-None-Match", "\"0123456789abcdef\"")
+            .build();
+        let response = handle_embedded_content(&request, "css/site.css", FILES, &config);
+        assert!(matches!(response.status, HttpCode::NotModified));
+
+        let response = handle_embedded_content(&request, "missing.css", FILES, &config);
+        assert!(matches!(response.status, HttpCode::NotFound));
+    }
+
+    #[test]
+    fn directory_requests_serve_the_index() {
+        let dir = TestDir::new("index");
+        dir.write("docs/index.html", b"<p>docs</p>");
+        let config = dir.config();
+
+        let request = RequestBuilder::new().path("/files/docs/").build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(
+            response.content_type.as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(
+            header(&response, "content-location"),
+            Some("/files/docs/index.html")
+        );
+        assert_eq!(response.content, b"<p>docs</p>");
+
+        // the file itself is its own canonical URL
+        let request = RequestBuilder::new().path("/files/docs/index.html").build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert_eq!(header(&response, "content-location"), None);
+    }
+
+    #[test]
+    fn doubled_slashes_are_merged_unless_kept() {
+        let dir = TestDir::new("slashes");
+        dir.write("sub/x", b"x");
+        let config = dir.config();
+        let get = |target: &str, config: &Config| {
+            let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+            let request = request::parse_request(&mut raw.as_bytes(), config)
+                .unwrap()
+                .unwrap();
+            handle_request(&request, &mut io::empty(), config).0
+        };
+
+        for target in ["/files//sub/x", "/files/sub///x", "//files/sub/x"] {
+            let response = get(target, &config);
+            assert!(matches!(response.status, HttpCode::Ok), "{target}");
+            assert_eq!(response.content, b"x");
+        }
+
+        // `--keep-double-slashes`: `/files//sub/x` names the absolute `/sub/x`
+        let config = Config {
+            merge_slashes: false,
+            ..config
+        };
+        let response = get("/files//sub/x", &config);
+        assert!(matches!(response.status, HttpCode::BadRequest));
+        assert!(matches!(get("/files/sub/x", &config).status, HttpCode::Ok));
+    }
+
+    #[test]
+    fn file_ranges_past_the_end_are_416() {
+        let dir = TestDir::new("range");
+        dir.write("ten.txt", b"0123456789");
+        let config = dir.config();
+
+        let ranged = |range: &str| {
+            let request = RequestBuilder::new()
+                .path("/files/ten.txt")
+                .header("Range", range)
+                .build();
+            handle_request(&request, &mut io::empty(), &config).0
+        };
+
+        for range in ["bytes=1000-2000", "bytes=10-", "bytes=10-10"] {
+            let response = ranged(range);
+            assert!(
+                matches!(response.status, HttpCode::RangeNotSatisfiable),
+                "{range}"
+            );
+            assert_eq!(header(&response, "content-range"), Some("bytes */10"));
+            assert!(response.content.is_empty());
+        }
+
+        // the end alone may run past it, it is cut to the last byte
+        let response = ranged("bytes=8-1000");
+        assert!(matches!(response.status, HttpCode::PartialContent));
+        assert_eq!(header(&response, "content-range"), Some("bytes 8-9/10"));
+        assert_eq!(response.content, b"89");
+    }
+
+    #[test]
+    fn locations_are_absolute_when_the_host_is_known() {
+        let dir = TestDir::new("location");
+        dir.write("docs/index.html", b"");
+        let config = Config {
+            scheme: "https".into(),
+            ..dir.config()
+        };
+
+        let request = RequestBuilder::new()
+            .path("/files/docs")
+            .header("Host", "example.com:8443")
+            .build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::MovedPermanently));
+        assert_eq!(
+            header(&response, "location"),
+            Some("https://example.com:8443/files/docs/")
+        );
+
+        // HTTP/1.0 clients may not send one, a relative reference still works
+        let request = RequestBuilder::new().path("/files/docs").build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert_eq!(header(&response, "location"), Some("/files/docs/"));
+    }
+
+    #[test]
+    fn content_disposition_encodes_unicode_names() {
+        assert_eq!(
+            content_disposition("docs/report.pdf"),
+            r#"attachment; filename="report.pdf""#
+        );
+        assert_eq!(
+            content_disposition("r\u{e9}sum\u{e9} 2024.pdf"),
+            r#"attachment; filename="r_sum_ 2024.pdf"; filename*=UTF-8''r%C3%A9sum%C3%A9%202024.pdf"#
+        );
+        // quotes would end the plain parameter early
+        assert_eq!(
+            content_disposition("say \"hi\".txt"),
+            r#"attachment; filename="say _hi_.txt"; filename*=UTF-8''say%20%22hi%22.txt"#
+        );
+    }
+
+    #[test]
+    fn nul_bytes_in_file_names_are_rejected() {
+        let dir = TestDir::new("nul");
+        dir.write("foo", b"foo");
+        let config = dir.config();
+
+        let request = RequestBuilder::new().path("/files/foo%00bar").build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::BadRequest));
+        assert_eq!(response.content, b"Invalid file name");
+
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/files/foo%00bar")
+            .body("oops")
+            .build();
+        let (response, _) = handle_request(&request, &mut &b"oops"[..], &config);
+        assert!(matches!(response.status, HttpCode::BadRequest));
+        assert_eq!(std::fs::read(dir.join("foo")).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn extensionless_files_are_sniffed_on_request() {
+        let dir = TestDir::new("sniff");
+        dir.write("logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        dir.write("page", b"\n<!DOCTYPE html><p>hi</p>");
+        dir.write("notes", "caf\u{e9}\n");
+        dir.write("blob", b"\0\x01\x02");
+        let mut config = Config {
+            sniff: true,
+            ..dir.config()
+        };
+        let content_type = |name: &str, config: &Config| {
+            let request = RequestBuilder::new()
+                .path(&format!("/files/{name}"))
+                .build();
+            handle_request(&request, &mut io::empty(), config)
+                .0
+                .content_type
+                .unwrap()
+        };
+
+        assert_eq!(content_type("logo", &config), "image/png");
+        assert_eq!(content_type("page", &config), "text/html; charset=utf-8");
+        assert_eq!(content_type("notes", &config), "text/plain; charset=utf-8");
+        assert_eq!(content_type("blob", &config), "application/octet-stream");
+
+        config.sniff = false;
+        assert_eq!(content_type("logo", &config), "application/octet-stream");
+    }
+
+    #[test]
+    fn insecure_requests_upgrade_behind_a_trusted_proxy() {
+        let config = Config {
+            trust_proxy: true,
+            ..Default::default()
+        };
+        let request = |proto: Option<&str>, upgrade: Option<&str>| {
+            let mut builder = RequestBuilder::new()
+                .path("/echo/abc")
+                .header("Host", "example.com:8080");
+            if let Some(proto) = proto {
+                builder = builder.header("X-Forwarded-Proto", proto);
+            }
+            if let Some(upgrade) = upgrade {
+                builder = builder.header("Upgrade-Insecure-Requests", upgrade);
+            }
+            builder.build()
+        };
+
+        let (response, _) =
+            handle_request(&request(Some("http"), Some("1")), &mut io::empty(), &config);
+        assert_eq!(response.status.code(), 307);
+        assert_eq!(
+            header(&response, "location"),
+            Some("https://example.com/echo/abc")
+        );
+        assert_eq!(header(&response, "vary"), Some("Upgrade-Insecure-Requests"));
+
+        for (proto, upgrade) in [
+            (Some("https"), Some("1")),
+            (None, Some("1")),
+            (Some("http"), None),
+            (Some("http"), Some("0")),
+        ] {
+            let (response, _) = handle_request(&request(proto, upgrade), &mut io::empty(), &config);
+            assert!(
+                matches!(response.status, HttpCode::Ok),
+                "{proto:?} {upgrade:?}"
+            );
+        }
+
+        // the header could come from the client itself
+        let response = handle(&request(Some("http"), Some("1")));
+        assert!(matches!(response.status, HttpCode::Ok));
+    }
+
+    #[test]
+    fn unimplemented_methods_are_501() {
+        for method in ["CONNECT", "DELETE", "BREW"] {
+            let response = handle(&RequestBuilder::new().method(method).path("/").build());
+            assert!(
+                matches!(response.status, HttpCode::NotImplemented),
+                "{method}"
+            );
+            assert_eq!(
+                header(&response, "allow"),
+                Some("GET, HEAD, POST, OPTIONS, TRACE")
+            );
+        }
+
+        // known to the server, just not on this resource
+        let response = handle(&RequestBuilder::new().method("POST").path("/").build());
+        assert!(matches!(response.status, HttpCode::MethodNotAllowed));
+    }
+
+    #[test]
+    fn unknown_range_units_are_ignored() {
+        let request = RequestBuilder::new()
+            .path("/echo/abcdef")
+            .header("Range", "items=0-5")
+            .build();
+        let response = handle(&request);
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content, b"abcdef");
+
+        let request = RequestBuilder::new()
+            .path("/echo/abcdef")
+            .header("Range", "Bytes=0-1")
+            .build();
+        assert_eq!(handle(&request).content, b"ab");
+    }
+
+    // the largest read asked of it: what the copy holds in memory at once
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+        largest: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.largest = self.largest.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn uploads_are_streamed_to_disk() {
+        let dir = TestDir::new("upload-stream");
+        let config = dir.config();
+
+        // far larger than any buffer on the way
+        let size = 8 << 20;
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/files/big.bin")
+            .header("Content-Length", &size.to_string())
+            .build();
+        let mut body = CountingReader {
+            inner: io::repeat(b'x').take(size),
+            reads: 0,
+            largest: 0,
+        };
+        let (response, _) = handle_request(&request, &mut body, &config);
+        assert!(matches!(response.status, HttpCode::Created));
+        assert!(request.body.is_empty());
+        assert_eq!(std::fs::metadata(dir.join("big.bin")).unwrap().len(), size);
+        // copied through one small buffer, never held whole
+        assert!(body.largest <= 64 * 1024, "read {} at once", body.largest);
+        assert!(
+            body.reads as u64 >= size / (64 * 1024),
+            "{} reads",
+            body.reads
+        );
+
+        // a chunked body is decoded on the way to the file
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/files/chunked.txt")
+            .header("Transfer-Encoding", "chunked")
+            .build();
+        let raw = b"6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\n\r\n";
+        let mut body = request::body_reader(&raw[..], &request);
+        let (response, _) = handle_request(&request, &mut body, &config);
+        assert!(matches!(response.status, HttpCode::Created));
+        assert_eq!(
+            std::fs::read(dir.join("chunked.txt")).unwrap(),
+            b"hello world"
+        );
+    }
+
+    #[test]
+    fn plain_http_is_redirected_to_https() {
+        let request = RequestBuilder::new()
+            .path("/echo/abc?x=1")
+            .header("Host", "example.com:8080")
+            .build();
+        let config = Config {
+            https_redirect: true,
+            ..Default::default()
+        };
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::MovedPermanently));
+        assert_eq!(
+            response.headers,
+            vec![(
+                "Location".to_string(),
+                "https://example.com/echo/abc?x=1".to_string()
+            )]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uploads_get_the_configured_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("mode");
+        let config = Config {
+            upload_mode: Some(0o640),
+            ..dir.config()
+        };
+
+        let request = RequestBuilder::new()
+            .method("POST")
+            .path("/files/upload.txt")
+            .build();
+        let (response, _) = handle_request(&request, &mut &b"data"[..], &config);
+        assert!(matches!(response.status, HttpCode::Created));
+
+        let metadata = std::fs::metadata(dir.join("upload.txt")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn delay_sleeps_and_validates() {
+        let started = std::time::Instant::now();
+        let response = handle(&RequestBuilder::new().path("/delay/1").build());
+        let elapsed = started.elapsed();
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content, br#"{"delay":1}"#);
+        assert!(elapsed >= std::time::Duration::from_secs(1), "{elapsed:?}");
+        assert!(elapsed < std::time::Duration::from_secs(2), "{elapsed:?}");
+
+        for path in ["/delay/abc", "/delay/11", "/delay/-1", "/delay/"] {
+            let response = handle(&RequestBuilder::new().path(path).build());
+            assert!(matches!(response.status, HttpCode::BadRequest), "{path}");
+        }
+    }
+
+    #[test]
+    fn disk_stats_sum_the_served_tree() {
+        let dir = TestDir::new("disk");
+        dir.write("top.txt", b"12345");
+        dir.write("a/one.txt", b"123");
+        dir.write("a/b/two.txt", b"12");
+        // a link back to the root would loop forever if followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path(), dir.join("a/b/loop")).unwrap();
+        let config = dir.config();
+
+        let request = RequestBuilder::new().path("/stats/disk").build();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::Ok));
+        assert_eq!(response.content_type.as_deref(), Some("application/json"));
+        assert_eq!(response.content, br#"{"files":3,"bytes":10}"#);
+
+        std::fs::remove_dir_all(dir.path()).unwrap();
+        let (response, _) = handle_request(&request, &mut io::empty(), &config);
+        assert!(matches!(response.status, HttpCode::InternalServerError));
+    }
+
+    #[test]
+    fn status_returns_any_code() {
+        let response = handle(&RequestBuilder::new().path("/status/418").build());
+        assert_eq!(response.status.to_string(), "418 I'm a teapot");
+        assert!(response.content.is_empty());
+        let response = handle(&RequestBuilder::new().path("/status/204").build());
+        assert!(matches!(response.status, HttpCode::NoContent));
+
+        let message = |path: &str| {
+            let response = handle(&RequestBuilder::new().path(path).build());
+            assert!(matches!(response.status, HttpCode::BadRequest), "{path}");
+            String::from_utf8(response.content).unwrap()
+        };
+        for path in ["/status/100", "/status/101", "/status/199"] {
+            assert_eq!(
+                message(path),
+                "Informational (1xx) codes cannot be sent as the response"
+            );
+        }
+        for path in ["/status/99", "/status/600", "/status/abc"] {
+            assert_eq!(message(path), "Status must be a code from 100 to 599");
+        }
+    }
+
+    #[test]
+    fn if_none_match_any_guards_existing_files() {
+        let dir = TestDir::new("none-match");
+        dir.write("taken.txt", "original");
+        let config = dir.config();
+
+        let get = |path: &str, star: bool| {
+            let mut builder = RequestBuilder::new().path(path);
+            if star {
+                builder = builder.header("If-None-Match", "*");
+            }
+            handle_request(&builder.build(), &mut io::empty(), &config).0
+        };
+        let response = get("/files/taken.txt", true);
+        assert!(matches!(response.status, HttpCode::NotModified));
+        assert!(response.content.is_empty());
+        assert!(matches!(
+            get("/files/taken.txt", false).status,
+            HttpCode::Ok
+        ));
+        assert!(matches!(
+            get("/files/missing.txt", true).status,
+            HttpCode::NotFound
+        ));
+
+        let upload = |path: &str, body: &str| {
+            let request = RequestBuilder::new()
+                .method("POST")
+                .path(path)
+                .header("If-None-Match", "*")
+                .header("Content-Length", &body.len().to_string())
+                .build();
+            handle_request(&request, &mut body.as_bytes(), &config).0
+        };
+        let response = upload("/files/taken.txt", "replaced");
+        assert!(matches!(response.status, HttpCode::PreconditionFailed));
+        assert_eq!(std::fs::read(dir.join("taken.txt")).unwrap(), b"original");
+
+        let response = upload("/files/fresh.txt", "created");
+        assert!(matches!(response.status, HttpCode::Created));
+        assert_eq!(std::fs::read(dir.join("fresh.txt")).unwrap(), b"created");
+    }
+}
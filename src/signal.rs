@@ -0,0 +1,29 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// bumped on every SIGHUP, consumers compare against the value they last saw
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn hangups() -> usize {
+    HANGUPS.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+pub(crate) fn install_sighup_handler() {
+    const SIGHUP: i32 = 1;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn on_sighup(_signum: i32) {
+        // only async-signal-safe work in here
+        HANGUPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe {
+        signal(SIGHUP, on_sighup);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn install_sighup_handler() {}
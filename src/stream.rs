@@ -0,0 +1,124 @@
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+
+// files at least this big are copied to the socket as they are read instead of
+// being loaded into memory first
+pub(crate) const STREAM_THRESHOLD: u64 = 1 << 20;
+
+// a response body written straight from its source
+pub(crate) struct StreamedBody {
+    file: File,
+    // where in the file the body starts, past the beginning for a byte range
+    offset: u64,
+    // of the identity representation
+    len: u64,
+}
+
+impl Debug for StreamedBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "StreamedBody({} bytes)", self.len)
+    }
+}
+
+impl StreamedBody {
+    pub(crate) fn new(file: File, len: u64) -> Self {
+        StreamedBody {
+            file,
+            offset: 0,
+            len,
+        }
+    }
+
+    // `len` bytes from `start` on, clamped to what this body holds
+    pub(crate) fn slice(self, start: u64, len: u64) -> Self {
+        let start = start.min(self.len);
+        StreamedBody {
+            offset: self.offset + start,
+            len: len.min(self.len - start),
+            ..self
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // compressed bodies go out chunked, their size is only known at the end
+    pub(crate) fn write_to(self, encoding: Option<&str>, out: &mut dyn Write) -> io::Result<()> {
+        let mut file = self.file;
+        if self.offset > 0 {
+            file.seek(SeekFrom::Start(self.offset))?;
+        }
+        let mut file = file.take(self.len);
+        match encoding {
+            Some("gzip") => {
+                let mut encoder = GzEncoder::new(ChunkedWriter::new(out), Compression::default());
+                io::copy(&mut file, &mut encoder)?;
+                encoder.finish()?.finish()
+            }
+            Some("deflate") => {
+                let mut encoder =
+                    DeflateEncoder::new(ChunkedWriter::new(out), Compression::default());
+                io::copy(&mut file, &mut encoder)?;
+                encoder.finish()?.finish()
+            }
+            _ => io::copy(&mut file, out).map(|_| ()),
+        }
+    }
+}
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// `Transfer-Encoding: chunked` framing, buffered so small writes don't each become
+// a chunk of their own
+pub(crate) struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        write!(self.inner, "{:x}\r\n", self.buffer.len())?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.write_all(b"\r\n")?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // the last chunk, the message ends here
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
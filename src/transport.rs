@@ -0,0 +1,123 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+// what a connection needs to be served, whatever socket it comes from
+pub(crate) trait Transport: Read + Write {
+    // `None` when the socket has no IP peer (unix domain sockets)
+    fn peer(&self) -> Option<SocketAddr>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    // a handle that can close the socket from another thread
+    fn closer(&self) -> io::Result<Box<dyn Closer>>;
+    // the peer reads end of file, we can still read what it sent
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+// shutting a socket down wakes up whoever is blocked reading it
+pub(crate) trait Closer: Send {
+    fn close(&self) -> io::Result<()>;
+}
+
+impl Closer for TcpStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Closer for UnixStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn closer(&self) -> io::Result<Box<dyn Closer>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn closer(&self) -> io::Result<Box<dyn Closer>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+// reads that all have to be done by one point in time: every read only waits for
+// what is left, a client trickling bytes cannot stretch it
+pub(crate) struct Deadline<'a, S: Transport> {
+    stream: &'a mut S,
+    deadline: Option<Instant>,
+    // the socket still has the timeout of the last read under a deadline
+    timeout_set: bool,
+}
+
+impl<'a, S: Transport> Deadline<'a, S> {
+    pub(crate) fn new(stream: &'a mut S) -> Self {
+        Deadline {
+            stream,
+            deadline: None,
+            timeout_set: false,
+        }
+    }
+
+    pub(crate) fn stream(&mut self) -> &mut S {
+        self.stream
+    }
+
+    pub(crate) fn start(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    // the socket waits forever again, before the next request starts idling on it
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        self.deadline = None;
+        if self.timeout_set {
+            self.stream.set_read_timeout(None)?;
+            self.timeout_set = false;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Transport> Read for Deadline<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or(io::ErrorKind::TimedOut)?;
+            self.stream.set_read_timeout(Some(remaining))?;
+            self.timeout_set = true;
+        }
+        self.stream.read(buf)
+    }
+}
@@ -0,0 +1,76 @@
+// the per-thread deflate encoder against a fresh one per response, counted with an
+// allocator of its own: kept out of the unit tests so none of them runs under it
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+#[path = "../src/compress.rs"]
+mod compress;
+
+// counts this thread's allocations, other threads can't skew a measurement
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+const RUNS: usize = 100;
+
+#[test]
+fn pooled_encoder_allocates_only_the_output() {
+    let content = "hello-hello-hello ".repeat(50);
+    // the first call on a thread creates its encoder
+    compress::gzip(content.as_bytes()).unwrap();
+    compress::deflate(content.as_bytes()).unwrap();
+
+    let pooled_gzip = allocations(|| {
+        for _ in 0..RUNS {
+            compress::gzip(content.as_bytes()).unwrap();
+        }
+    });
+    let pooled_deflate = allocations(|| {
+        for _ in 0..RUNS {
+            compress::deflate(content.as_bytes()).unwrap();
+        }
+    });
+    let fresh = allocations(|| {
+        for _ in 0..RUNS {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content.as_bytes()).unwrap();
+            encoder.finish().unwrap();
+        }
+    });
+
+    // one output buffer per response, sized up front
+    assert_eq!(pooled_gzip, RUNS);
+    assert_eq!(pooled_deflate, RUNS);
+    // the encoder state alone is several allocations
+    assert!(
+        fresh >= 5 * RUNS,
+        "fresh encoder: {fresh} for {RUNS} responses"
+    );
+}